@@ -0,0 +1,41 @@
+/// The stations of the challenge's data generator.
+pub const STATIONS: &str = include_str!("../stations.txt");
+
+/// A linear congruential generator, so every run benchmarks the same
+/// inputs.
+pub struct Lcg(pub u64);
+
+impl Lcg {
+    /// Returns the next state, whose high bits are the most random.
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1);
+        self.0
+    }
+}
+
+/// n names shaped like GHCN station IDs: a country, a network and a
+/// number, all the same length.
+pub fn station_ids(n: usize) -> Vec<String> {
+    let mut lcg = Lcg(1);
+    (0..n)
+        .map(|_| {
+            let state = lcg.next();
+            let country = ["US", "CA", "MX", "GM", "AS"][(state >> 40) as usize % 5];
+            let network = ["C", "W", "1"][(state >> 50) as usize % 3];
+            format!("{country}{network}{:08}", (state >> 33) % 100_000_000)
+        })
+        .collect()
+}
+
+/// Copies each name into 64 zeroed bytes, as names are followed by
+/// padding in a StationNameKey or a line.
+pub fn padded(names: &[String]) -> Vec<[u8; 64]> {
+    names
+        .iter()
+        .map(|name| {
+            let mut padded = [0; 64];
+            padded[..name.len()].copy_from_slice(name.as_bytes());
+            padded
+        })
+        .collect()
+}
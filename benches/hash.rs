@@ -0,0 +1,135 @@
+use std::{collections::HashMap, hint::black_box};
+
+use brc::station_map::{DEFAULT_HASH_SEED, hash64, hash64_with};
+use common::{STATIONS, padded, station_ids};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+
+mod common;
+
+/// The hash this replaced, which only looked at the length and the
+/// bytes at 0, len/4, len/2 and len-1.
+#[inline(always)]
+fn sampled_hash(bytes: &[u8]) -> u64 {
+    const SEED: u64 = 0xf1357aea2e62a9c5;
+    let len = bytes.len();
+    if len == 0 {
+        return SEED;
+    }
+    let x = ((bytes[0] as u64) << 56)
+        | ((bytes[len / 4] as u64) << 48)
+        | ((bytes[len / 2] as u64) << 40)
+        | ((bytes[len - 1] as u64) << 32);
+    let mut hash = x ^ (len as u64);
+    hash = hash.wrapping_mul(SEED);
+    hash ^= hash >> 32;
+    hash = hash.wrapping_mul(SEED);
+    hash ^= hash >> 32;
+    hash
+}
+
+/// Counts the names that share their hash with another name, and those
+/// that share their first slot and 32 bit tag in a StationMap of 65536
+/// slots, as the binary's map of 12000 stations has. A lookup of either
+/// kind of name has to compare names to find its entry.
+fn collisions(names: &[&[u8]], hash: impl Fn(&[u8]) -> u64) -> (usize, usize) {
+    let mut hashes = HashMap::<u64, usize>::new();
+    let mut slots = HashMap::<(u64, u64), usize>::new();
+    for name in names {
+        let h = hash(name);
+        *hashes.entry(h).or_default() += 1;
+        *slots.entry((h & 65535, h >> 32)).or_default() += 1;
+    }
+    let colliding = |counts: Vec<usize>| counts.into_iter().filter(|&n| n > 1).sum();
+    (
+        colliding(hashes.into_values().collect()),
+        colliding(slots.into_values().collect()),
+    )
+}
+
+#[inline(always)]
+fn hash_names(names: &[&[u8]], hash: impl Fn(&[u8]) -> u64) -> u64 {
+    names.iter().fold(0, |acc, name| acc ^ hash(name))
+}
+
+/// Compiles the hash loop for M with its target feature enabled, like
+/// the binary's hot loop is.
+macro_rules! level_benches {
+    ($level:ident, $m:ty $(, $feature:literal)?) => {
+        mod $level {
+            use brc::memops::Memops;
+
+            pub const LEVEL: brc::memops::SimdLevel = <$m>::LEVEL;
+
+            $(#[target_feature(enable = $feature)])?
+            pub unsafe fn hash_names(names: &[&[u8]]) -> u64 {
+                super::hash_names(names, |name| unsafe { super::hash64_with::<$m>(name, super::DEFAULT_HASH_SEED) })
+            }
+        }
+    };
+}
+
+level_benches!(scalar, brc::memops::Scalar);
+level_benches!(swar, brc::memops::Swar);
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+level_benches!(sse2, brc::memops::Sse2);
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+level_benches!(avx2, brc::memops::Avx2, "avx2");
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+level_benches!(avx512, brc::memops::Avx512, "avx512bw");
+
+macro_rules! bench_level {
+    ($group:expr, $names:expr, $level:ident) => {
+        if $level::LEVEL.is_supported() {
+            $group.bench_function(stringify!($level), |b| {
+                b.iter(|| unsafe { $level::hash_names(black_box($names)) })
+            });
+        }
+    };
+}
+
+fn bench_list(c: &mut Criterion, list: &str, names: &[String]) {
+    let padded = padded(names);
+    let names: Vec<&[u8]> = names
+        .iter()
+        .zip(&padded)
+        .map(|(name, padded)| &padded[..name.len()])
+        .collect();
+
+    for (hash_name, (same_hash, same_slot)) in [
+        ("sampled", collisions(&names, sampled_hash)),
+        (
+            "full",
+            collisions(&names, |name| hash64(name, DEFAULT_HASH_SEED)),
+        ),
+    ] {
+        println!(
+            "{list}: {} names, {hash_name} hash: {same_hash} share a hash, \
+             {same_slot} share a slot and tag",
+            names.len()
+        );
+    }
+
+    let mut group = c.benchmark_group(format!("hash/{list}"));
+    group.throughput(Throughput::Elements(names.len() as u64));
+    group.bench_function("sampled", |b| {
+        b.iter(|| hash_names(black_box(&names), sampled_hash))
+    });
+    bench_level!(group, &names, scalar);
+    bench_level!(group, &names, swar);
+    #[cfg(all(target_arch = "x86_64", not(miri)))]
+    {
+        bench_level!(group, &names, sse2);
+        bench_level!(group, &names, avx2);
+        bench_level!(group, &names, avx512);
+    }
+    group.finish();
+}
+
+fn bench_hash(c: &mut Criterion) {
+    let stations: Vec<String> = STATIONS.lines().map(str::to_owned).collect();
+    bench_list(c, "stations", &stations);
+    bench_list(c, "station_ids", &station_ids(10_000));
+}
+
+criterion_group!(benches, bench_hash);
+criterion_main!(benches);
@@ -0,0 +1,154 @@
+use std::hint::black_box;
+
+use brc::{
+    batched_lines::{IterationControl, batched_process_lines},
+    memops::Memops,
+};
+use common::Lcg;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+
+// Only the generator is used here.
+#[allow(dead_code)]
+mod common;
+
+/// Lines with station names of 3 to 40 bytes, like the challenge's data.
+fn lines() -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut lcg = Lcg(1);
+    while data.len() < 1 << 16 {
+        let name_len = 3 + (lcg.next() >> 33) as usize % 38;
+        data.extend((0..name_len).map(|i| b'a' + (i % 26) as u8));
+        data.extend_from_slice(b";-12.3\n");
+    }
+    data
+}
+
+#[inline(always)]
+fn bitmask_fields<M: Memops>(data: &[u8]) -> usize {
+    let mut sum = 0;
+    let mut tail_buffer = Vec::new();
+    batched_process_lines::<M, 4, b';', _, _>(
+        data,
+        0,
+        &mut tail_buffer,
+        #[inline(always)]
+        |_, delim_indexes| {
+            sum += delim_indexes.iter().sum::<usize>();
+            IterationControl::Continue
+        },
+        |_| Ok(()),
+    )
+    .unwrap();
+    sum
+}
+
+/// Loads 32 bytes from the start of each line, as `Slot::holds` does for
+/// the ends of long names.
+#[inline(always)]
+fn load32<M: Memops>(lines: &[&[u8]]) -> u64 {
+    let mut sum = 0;
+    for line in lines {
+        let words = unsafe { M::load32_unchecked(line) };
+        sum ^= words[0] ^ words[3];
+    }
+    sum
+}
+
+/// Compiles the benchmark loops for M with its target feature enabled,
+/// like the binary's hot loop is, so that M's memops are inlined.
+macro_rules! level_benches {
+    ($level:ident, $m:ty $(, $feature:literal)?) => {
+        mod $level {
+            use brc::memops::Memops;
+
+            pub const LEVEL: brc::memops::SimdLevel = <$m>::LEVEL;
+
+            $(#[target_feature(enable = $feature)])?
+            pub unsafe fn bitmask_fields(data: &[u8]) -> usize {
+                super::bitmask_fields::<$m>(data)
+            }
+
+            $(#[target_feature(enable = $feature)])?
+            pub unsafe fn load32(lines: &[&[u8]]) -> u64 {
+                super::load32::<$m>(lines)
+            }
+        }
+    };
+}
+
+level_benches!(scalar, brc::memops::Scalar);
+level_benches!(swar, brc::memops::Swar);
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+level_benches!(sse2, brc::memops::Sse2);
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+level_benches!(avx2, brc::memops::Avx2, "avx2");
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+level_benches!(avx512, brc::memops::Avx512, "avx512bw");
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+level_benches!(bounded_sse2, brc::memops::Bounded<brc::memops::Sse2>);
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+level_benches!(
+    bounded_avx2,
+    brc::memops::Bounded<brc::memops::Avx2>,
+    "avx2"
+);
+
+macro_rules! bench_level {
+    ($c:expr, $level:ident) => {
+        if $level::LEVEL.is_supported() {
+            bench_level(
+                $c,
+                stringify!($level),
+                |data| unsafe { $level::bitmask_fields(data) },
+                |lines| unsafe { $level::load32(lines) },
+            );
+        }
+    };
+}
+
+fn bench_level(
+    c: &mut Criterion,
+    name: &str,
+    bitmask_fields: impl Fn(&[u8]) -> usize,
+    load32: impl Fn(&[&[u8]]) -> u64,
+) {
+    // Followed by enough zeroes for levels that read past the end of the
+    // last line.
+    let mut data = lines();
+    let len = data.len();
+    data.resize(len + 32, 0);
+    let lines: Vec<&[u8]> = data[..len]
+        .split_inclusive(|&c| c == b'\n')
+        .map(|line| &line[..line.len() - 1])
+        .collect();
+
+    // Finds every line's delimiter, copying the last 128 bytes to scan
+    // them.
+    let mut group = c.benchmark_group("fields");
+    group.throughput(Throughput::Elements(lines.len() as u64));
+    group.bench_function(name, |b| b.iter(|| bitmask_fields(black_box(&data[..len]))));
+    group.finish();
+
+    // Bounded levels check every line's page, and copy the lines near the
+    // end of one.
+    let mut group = c.benchmark_group("load32");
+    group.throughput(Throughput::Elements(lines.len() as u64));
+    group.bench_function(name, |b| b.iter(|| load32(black_box(&lines))));
+    group.finish();
+}
+
+fn bench_memops(c: &mut Criterion) {
+    bench_level!(c, scalar);
+    bench_level!(c, swar);
+    #[cfg(all(target_arch = "x86_64", not(miri)))]
+    {
+        bench_level!(c, sse2);
+        bench_level!(c, avx2);
+        bench_level!(c, avx512);
+        bench_level!(c, bounded_sse2);
+        bench_level!(c, bounded_avx2);
+    }
+}
+
+criterion_group!(benches, bench_memops);
+criterion_main!(benches);
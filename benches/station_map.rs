@@ -0,0 +1,221 @@
+use std::hint::black_box;
+
+use brc::{
+    mmap_allocator::{AllocatorOptions, MmapAllocator},
+    station_map::{
+        DEFAULT_HASH_SEED, NameHash, StationMap, StationMapOptions, StationNameKey, hash64,
+        new_station_map,
+    },
+    temperature_summary::{PackedTemperatureSummary, TemperatureSummary},
+};
+use common::{Lcg, STATIONS, padded, station_ids};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use hashbrown::hash_map::RawEntryMut;
+
+mod common;
+
+/// A baseline level, so neither map needs a target feature enabled.
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+type M = brc::memops::Sse2;
+#[cfg(not(all(target_arch = "x86_64", not(miri))))]
+type M = brc::memops::Swar;
+
+/// The map StationMap replaced, allocating from MmapAllocator as it did.
+/// Lookups pass the hash, so it has no hasher.
+type HashbrownMap = hashbrown::HashMap<StationNameKey, TemperatureSummary, (), MmapAllocator>;
+
+fn hashbrown_map(names: &[&str]) -> HashbrownMap {
+    let mut map = HashbrownMap::with_capacity_and_hasher_in(
+        12_000,
+        (),
+        MmapAllocator::new(&AllocatorOptions {
+            request_hugepage: false,
+        }),
+    );
+    let hash = |name: &str| hash64(name.as_bytes(), DEFAULT_HASH_SEED);
+    for &name in names {
+        let entry = map
+            .raw_entry_mut()
+            .from_hash(hash(name), |k| k.as_str() == name);
+        if let RawEntryMut::Vacant(entry) = entry {
+            entry.insert_with_hasher(
+                hash(name),
+                StationNameKey::new(name),
+                TemperatureSummary::default(),
+                |k| hash(k.as_str()),
+            );
+        }
+    }
+    map
+}
+
+fn station_map(names: &[&str]) -> StationMap<TemperatureSummary> {
+    let mut map = new_station_map(&StationMapOptions {
+        request_hugepage: false,
+        capacity: 12_000,
+        name_hash: NameHash::Fast(DEFAULT_HASH_SEED),
+    });
+    for &name in names {
+        map.get_or_default(name);
+    }
+    map
+}
+
+/// Picks the names of 65536 lookups, either uniformly, or skewed so that
+/// a quarter of the lookups go to the first 1/256th of the names.
+fn lookups<'a>(names: &[&'a str], skewed: bool) -> Vec<(u64, &'a str)> {
+    let mut lcg = Lcg(1);
+    (0..1 << 16)
+        .map(|_| {
+            let u = (lcg.next() >> 11) as f64 / (1u64 << 53) as f64;
+            let i = if skewed {
+                (u.powi(4) * names.len() as f64) as usize
+            } else {
+                (u * names.len() as f64) as usize
+            };
+            (hash64(names[i].as_bytes(), DEFAULT_HASH_SEED), names[i])
+        })
+        .collect()
+}
+
+#[inline(never)]
+fn hashbrown_lookups(map: &HashbrownMap, lookups: &[(u64, &str)]) {
+    for &(hash, name) in lookups {
+        let entry = map.raw_entry().from_hash(hash, |k| k.as_str() == name);
+        if let Some((_, summary)) = entry {
+            summary.add_reading(1);
+        }
+    }
+}
+
+/// Like `station_map_lookups`, prefetching the slot of the name distance
+/// lookups ahead, as the batched loop prefetches whole batches ahead.
+#[inline(never)]
+fn prefetched_lookups(
+    map: &StationMap<TemperatureSummary>,
+    lookups: &[(u64, &str)],
+    distance: usize,
+) {
+    for i in 0..lookups.len() {
+        if let Some(&(hash, _)) = lookups.get(i + distance) {
+            map.prefetch(hash);
+        }
+        let (hash, name) = lookups[i];
+        if let Some(summary) = unsafe { map.get_with::<M>(hash, name) } {
+            summary.add_reading(1);
+        }
+    }
+}
+
+#[inline(never)]
+fn station_map_lookups(map: &StationMap<TemperatureSummary>, lookups: &[(u64, &str)]) {
+    for &(hash, name) in lookups {
+        if let Some(summary) = unsafe { map.get_with::<M>(hash, name) } {
+            summary.add_reading(1);
+        }
+    }
+}
+
+/// Views each padded name as the str it starts with.
+fn padded_names<'a>(names: &[String], padded: &'a [[u8; 64]]) -> Vec<&'a str> {
+    names
+        .iter()
+        .zip(padded)
+        .map(|(name, padded)| unsafe { std::str::from_utf8_unchecked(&padded[..name.len()]) })
+        .collect()
+}
+
+fn bench_list(c: &mut Criterion, list: &str, names: &[String]) {
+    let padded = padded(names);
+    let names = padded_names(names, &padded);
+    let hashbrown_map = hashbrown_map(&names);
+    let station_map = station_map(&names);
+
+    for (distribution, skewed) in [("uniform", false), ("skewed", true)] {
+        let lookups = lookups(&names, skewed);
+        let mut group = c.benchmark_group(format!("station_map/{list}/{distribution}"));
+        group.throughput(Throughput::Elements(lookups.len() as u64));
+        group.bench_function("hashbrown", |b| {
+            b.iter(|| hashbrown_lookups(&hashbrown_map, black_box(&lookups)))
+        });
+        group.bench_function("station_map", |b| {
+            b.iter(|| station_map_lookups(&station_map, black_box(&lookups)))
+        });
+        group.finish();
+    }
+}
+
+/// Compares prefetch distances, in lookups, on a map that stays in L1 or
+/// L2, one that fits in L3 and one far bigger than it.
+fn bench_prefetch_list(c: &mut Criterion, list: &str, names: &[String]) {
+    let padded = padded(names);
+    let names = padded_names(names, &padded);
+    let map = station_map(&names);
+    let lookups = lookups(&names, false);
+    let mut group = c.benchmark_group(format!("station_map_prefetch/{list}"));
+    group.throughput(Throughput::Elements(lookups.len() as u64));
+    for distance in [0, 4, 8, 16, 32, 64] {
+        group.bench_function(format!("distance_{distance}"), |b| {
+            b.iter(|| prefetched_lookups(&map, black_box(&lookups), distance))
+        });
+    }
+    group.finish();
+}
+
+/// Picks 65536 indexes of n summaries uniformly.
+fn summary_indexes(n: usize) -> Vec<usize> {
+    let mut lcg = Lcg(1);
+    (0..1 << 16)
+        .map(|_| ((lcg.next() >> 33) as usize * n) >> 31)
+        .collect()
+}
+
+#[inline(never)]
+fn wide_summary_updates(summaries: &[TemperatureSummary], indexes: &[usize]) {
+    for (i, &index) in indexes.iter().enumerate() {
+        summaries[index].add_reading(i as i32 % 1999 - 999);
+    }
+}
+
+#[inline(never)]
+fn packed_summary_updates(summaries: &[PackedTemperatureSummary], indexes: &[usize]) {
+    for (i, &index) in indexes.iter().enumerate() {
+        summaries[index].add_reading(i as i32 % 1999 - 999);
+    }
+}
+
+/// Compares updating 32 and 16 byte summaries in a dense array, such as a
+/// `PerfectStationMap` of the names would be, at sizes where the packed
+/// array fits in a cache level that the other doesn't.
+fn bench_summaries(c: &mut Criterion) {
+    for n in [413, 10_000, 100_000, 1_000_000] {
+        let indexes = summary_indexes(n);
+        let wide: Vec<TemperatureSummary> = (0..n).map(|_| Default::default()).collect();
+        let packed: Vec<PackedTemperatureSummary> = (0..n).map(|_| Default::default()).collect();
+        let mut group = c.benchmark_group(format!("summaries/{n}"));
+        group.throughput(Throughput::Elements(indexes.len() as u64));
+        group.bench_function("wide", |b| {
+            b.iter(|| wide_summary_updates(&wide, black_box(&indexes)))
+        });
+        group.bench_function("packed", |b| {
+            b.iter(|| packed_summary_updates(&packed, black_box(&indexes)))
+        });
+        group.finish();
+    }
+}
+
+fn bench_station_map(c: &mut Criterion) {
+    let stations: Vec<String> = STATIONS.lines().map(str::to_owned).collect();
+    bench_list(c, "stations", &stations);
+    bench_list(c, "station_ids", &station_ids(10_000));
+}
+
+fn bench_prefetch(c: &mut Criterion) {
+    let stations: Vec<String> = STATIONS.lines().map(str::to_owned).collect();
+    bench_prefetch_list(c, "stations", &stations);
+    bench_prefetch_list(c, "station_ids", &station_ids(10_000));
+    bench_prefetch_list(c, "station_ids_500k", &station_ids(500_000));
+}
+
+criterion_group!(benches, bench_station_map, bench_prefetch, bench_summaries);
+criterion_main!(benches);
@@ -21,3 +21,18 @@ pub fn unlikely(b: bool) -> bool {
         false
     }
 }
+
+/// Hints that the cache line at ptr will be read soon. Prefetches never
+/// fault, so ptr needn't be valid.
+///
+/// This does nothing on targets other than x86-64.
+#[inline(always)]
+pub fn prefetch_read<T>(ptr: *const T) {
+    #[cfg(all(target_arch = "x86_64", not(miri)))]
+    unsafe {
+        use std::arch::x86_64::{_MM_HINT_T0, _mm_prefetch};
+        _mm_prefetch::<_MM_HINT_T0>(ptr as *const i8);
+    }
+    #[cfg(not(all(target_arch = "x86_64", not(miri))))]
+    let _ = ptr;
+}
@@ -0,0 +1,371 @@
+use std::fs::File;
+
+use memmap2::{Mmap, MmapOptions};
+
+use crate::{error::BrcResult, memops::Memops};
+
+pub enum IterationControl {
+    Continue,
+    Break,
+}
+
+/// The number of bytes at the end of the input that are scanned from the
+/// tail buffer rather than in place.
+///
+/// Lines of a batch may be read up to 64 bytes past their start, and a
+/// line ending in the block being scanned starts at most 64 bytes past the
+/// block's start, so only blocks with another 64 bytes after them are
+/// scanned.
+pub const TAIL_SIZE: usize = 128;
+
+#[inline(never)]
+fn drop_mmap_range(mmap: &memmap2::Mmap, start: usize, size: usize) -> BrcResult<()> {
+    unsafe {
+        mmap.unchecked_advise_range(memmap2::UncheckedAdvice::DontNeed, start, size)?;
+    }
+    Ok(())
+}
+
+/// Memory maps `file` for `batched_process_lines`.
+pub fn map_file(file: &File) -> BrcResult<Mmap> {
+    let mmap = unsafe { MmapOptions::new().map(file)? };
+    mmap.advise(memmap2::Advice::Sequential)?;
+    mmap.advise(memmap2::Advice::WillNeed)?;
+    Ok(mmap)
+}
+
+/// Madvises DONTNEED on the pages of a file mapped by `map_file` that
+/// `batched_process_lines` has passed, 256MiB at a time, so that resident
+/// memory stays small.
+///
+/// This is actually a tiny bit of a performance hit,
+/// but it stops htop from reporting GiBs of memory usage.
+pub struct DontNeedBarrier<'a> {
+    mmap: Option<&'a Mmap>,
+    barrier: usize,
+}
+
+impl<'a> DontNeedBarrier<'a> {
+    const SIZE: usize = 256usize << 20;
+
+    /// A barrier for lines read from the start of mmap, or one that does
+    /// nothing for lines that weren't read from a mapping.
+    pub fn new(mmap: Option<&'a Mmap>) -> Self {
+        DontNeedBarrier {
+            mmap,
+            barrier: Self::SIZE,
+        }
+    }
+
+    /// Drops the pages before cursor, for a `progress_callback`.
+    #[inline(always)]
+    pub fn advance(&mut self, cursor: usize) -> BrcResult<()> {
+        // This ensures we don't keep too much data in RAM.
+        if let Some(mmap) = self.mmap
+            && cursor >= self.barrier
+        {
+            drop_mmap_range(mmap, self.barrier - Self::SIZE, Self::SIZE)?;
+            self.barrier += Self::SIZE;
+        }
+        Ok(())
+    }
+}
+
+/// Splits `data` into lines, passing them to `batch_callback` N at a time
+/// along with the index of the last DELIM in each line, or fewer than N
+/// in the last batch. `progress_callback` is called with the cursor after
+/// every full batch.
+///
+/// Rather than searching for the end of each line and then for its
+/// delimiter, `data` is scanned once in 64 byte blocks, building bitmasks
+/// of the newlines and delimiters in each block. Line and field boundaries
+/// are then read off the masks with trailing_zeros and clearing the lowest
+/// set bit, which compile to tzcnt and blsr.
+///
+/// The blocks near the end of `data`, and the lines of the batch they
+/// finish, are copied into `tail_buffer` with zero padding after them, and
+/// scanned there in the same way. So there is only one path for lines, and
+/// a last line without a newline gets one.
+///
+/// A line without a delimiter gets its length as the delimiter index.
+///
+/// Lines may be of any length, though callers only take names of up to
+/// [`MAX_NAME_LEN`] bytes to a station map. Lines are slices of `data` or
+/// `tail_buffer`, so callbacks may hold on to them and may read up to 64
+/// bytes past the start of each of them.
+///
+/// [`MAX_NAME_LEN`]: crate::station_map::MAX_NAME_LEN
+///
+/// This is always inlined so that it gets compiled with the target
+/// features of the function M was picked in.
+#[cfg_attr(feature = "profiled", inline(never))]
+#[cfg_attr(not(feature = "profiled"), inline(always))]
+pub fn batched_process_lines<'a, M: Memops, const N: usize, const DELIM: u8, FN, FP>(
+    data: &'a [u8],
+    skip_lines: usize,
+    tail_buffer: &'a mut Vec<u8>,
+    mut batch_callback: FN,
+    mut progress_callback: FP,
+) -> BrcResult<()>
+where
+    FN: FnMut(&[&'a [u8]], &[usize]) -> IterationControl,
+    FP: FnMut(usize) -> BrcResult<()>,
+{
+    let mut cursor: usize = 0;
+
+    // Skip over any header lines, which may be arbitrarily long.
+    for _ in 0..skip_lines {
+        cursor = match data[cursor..].iter().position(|&c| c == b'\n') {
+            Some(newline_idx) => cursor + newline_idx + 1,
+            None => data.len(),
+        };
+    }
+
+    let Some(batch_start) = scan_lines::<M, N, DELIM, _, _>(
+        data,
+        cursor,
+        false,
+        &mut batch_callback,
+        &mut progress_callback,
+    )?
+    else {
+        return Ok(());
+    };
+
+    let rest = &data[batch_start..];
+    tail_buffer.clear();
+    tail_buffer.extend_from_slice(rest);
+    if rest.last().is_some_and(|&c| c != b'\n') {
+        tail_buffer.push(b'\n');
+    }
+    // Enough that every block with a byte of rest in it gets scanned.
+    tail_buffer.resize(tail_buffer.len().next_multiple_of(64) + TAIL_SIZE, 0);
+    let tail: &'a [u8] = tail_buffer;
+
+    scan_lines::<M, N, DELIM, _, _>(tail, 0, true, &mut batch_callback, &mut |_| Ok(()))?;
+    Ok(())
+}
+
+/// Scans `data` from `cursor` for `batched_process_lines`, up to the last
+/// block with `TAIL_SIZE` bytes from its start to the end of data.
+///
+/// Returns the start of the first line that wasn't passed to
+/// `batch_callback`, or None if it returned Break. If `last` is set, the
+/// lines of the unfinished batch are passed to it too.
+#[inline(always)]
+fn scan_lines<'a, M: Memops, const N: usize, const DELIM: u8, FN, FP>(
+    data: &'a [u8],
+    mut cursor: usize,
+    last: bool,
+    batch_callback: &mut FN,
+    progress_callback: &mut FP,
+) -> BrcResult<Option<usize>>
+where
+    FN: FnMut(&[&'a [u8]], &[usize]) -> IterationControl,
+    FP: FnMut(usize) -> BrcResult<()>,
+{
+    let mut lines: [&[u8]; N] = [&[]; N];
+    let mut delim_indexes = [0usize; N];
+    let mut batched = 0;
+    let mut batch_start = cursor;
+
+    // The absolute index of the last delimiter before the current
+    // newline. It may be in an earlier line, in which case the subtraction
+    // below wraps and the index is clamped to the line length, so
+    // delimiters don't need clearing from the mask once their line is done.
+    let mut last_delim = usize::MAX;
+
+    let mut block_start = cursor;
+    while block_start + TAIL_SIZE <= data.len() {
+        let block = unsafe { &*(data.as_ptr().add(block_start) as *const [u8; 64]) };
+        let mut newlines = unsafe { M::eq_mask64::<b'\n'>(block) };
+        let delims = unsafe { M::eq_mask64::<DELIM>(block) };
+
+        while newlines != 0 {
+            let newline_bit = newlines.trailing_zeros() as usize;
+            let delims_before = delims & ((1 << newline_bit) - 1);
+            if delims_before != 0 {
+                last_delim = block_start + 63 - delims_before.leading_zeros() as usize;
+            }
+            newlines &= newlines - 1;
+
+            let line_end = block_start + newline_bit;
+            let line = unsafe { data.get_unchecked(cursor..line_end) };
+            // batched < N, which the compiler can't see across iterations.
+            unsafe {
+                *lines.get_unchecked_mut(batched) = line;
+                *delim_indexes.get_unchecked_mut(batched) =
+                    last_delim.wrapping_sub(cursor).min(line.len());
+            }
+            cursor = line_end + 1;
+            batched += 1;
+
+            if batched == N {
+                if let IterationControl::Break = batch_callback(&lines, &delim_indexes) {
+                    return Ok(None);
+                }
+                progress_callback(cursor)?;
+                batched = 0;
+                batch_start = cursor;
+            }
+        }
+
+        if delims != 0 {
+            last_delim = block_start + 63 - delims.leading_zeros() as usize;
+        }
+        block_start += 64;
+    }
+
+    if last
+        && batched != 0
+        && let IterationControl::Break =
+            batch_callback(&lines[..batched], &delim_indexes[..batched])
+    {
+        return Ok(None);
+    }
+    Ok(Some(batch_start))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        batched_lines::{IterationControl, TAIL_SIZE, batched_process_lines},
+        guard_page::GuardedPages,
+        memops::{Memops, test_all_levels},
+        test_data::Rng,
+    };
+
+    /// Runs `data` through batched_process_lines with the last byte of
+    /// `data` right before a PROT_NONE page, returning the lines seen and
+    /// checking their delimiter indexes and that only the last batch is
+    /// short.
+    fn guarded_lines<M: Memops, const N: usize>(
+        pages: &mut GuardedPages,
+        data: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let guarded = pages.tail_mut(data.len());
+        guarded.copy_from_slice(data);
+
+        let mut lines = Vec::new();
+        let mut short_batch = false;
+        let mut tail_buffer = Vec::new();
+        batched_process_lines::<M, N, b';', _, _>(
+            guarded,
+            0,
+            &mut tail_buffer,
+            |batch, delim_indexes| {
+                assert!(!short_batch, "a short batch was not the last");
+                short_batch = batch.len() < N;
+                for (line, &delim_idx) in batch.iter().zip(delim_indexes) {
+                    let expected = line.iter().rposition(|&c| c == b';');
+                    assert_eq!(delim_idx, expected.unwrap_or(line.len()));
+                    // Batch callbacks may read 64 bytes from the start of
+                    // each line.
+                    std::hint::black_box(unsafe {
+                        std::ptr::read_unaligned(line.as_ptr() as *const [u8; 64])
+                    });
+                    lines.push(line.to_vec());
+                }
+                IterationControl::Continue
+            },
+            |_| Ok(()),
+        )
+        .unwrap();
+        lines
+    }
+
+    fn expected_lines(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut lines: Vec<Vec<u8>> = data.split(|&c| c == b'\n').map(|l| l.to_vec()).collect();
+        if data.last().is_none_or(|&c| c == b'\n') {
+            lines.pop();
+        }
+        lines
+    }
+
+    /// Builds `total` bytes of lines, all `line_len` long including the newline.
+    fn uniform_lines(total: usize, line_len: usize, trailing_newline: bool) -> Vec<u8> {
+        let mut data: Vec<u8> = (0..total)
+            .map(|i| {
+                if i % line_len == line_len - 1 {
+                    b'\n'
+                } else {
+                    b"abc;de;fgh"[i % 10]
+                }
+            })
+            .collect();
+        if let Some(last) = data.last_mut() {
+            *last = if trailing_newline { b'\n' } else { b'z' };
+        }
+        data
+    }
+
+    fn check_tail_never_reads_past_end<M: Memops>() {
+        let mut pages = GuardedPages::new(1);
+        for line_len in [1, 2, 7, 33, 63, 64] {
+            for total in 0..=(4 * 65 + 3 * TAIL_SIZE) {
+                for trailing_newline in [true, false] {
+                    let data = uniform_lines(total, line_len, trailing_newline);
+                    assert_eq!(
+                        guarded_lines::<M, 4>(&mut pages, &data),
+                        expected_lines(&data),
+                        "line_len={line_len} total={total}"
+                    );
+                }
+            }
+        }
+    }
+
+    test_all_levels!(
+        #[cfg_attr(miri, ignore)]
+        test_tail_never_reads_past_end,
+        check_tail_never_reads_past_end
+    );
+
+    fn check_tail_never_reads_past_end_with_mixed_lines<M: Memops>() {
+        let mut pages = GuardedPages::new(1);
+        let mut data = Vec::new();
+        let mut len = 1;
+        while data.len() < 2048 {
+            data.extend((1..len).map(|i| if i % 5 == 0 { b';' } else { b'x' }));
+            data.push(b'\n');
+            len = len * 7 % 64 + 1;
+        }
+        for start in 0..data.len() {
+            assert_eq!(
+                guarded_lines::<M, 4>(&mut pages, &data[start..]),
+                expected_lines(&data[start..])
+            );
+        }
+    }
+
+    test_all_levels!(
+        #[cfg_attr(miri, ignore)]
+        test_tail_never_reads_past_end_with_mixed_lines,
+        check_tail_never_reads_past_end_with_mixed_lines
+    );
+
+    fn check_delimiter_indexes<M: Memops>() {
+        let mut pages = GuardedPages::new(2);
+        let mut rng = Rng(1);
+        for _ in 0..20 {
+            // Lines of up to 64 bytes with any number of delimiters, so that
+            // lines and their delimiters straddle block boundaries.
+            let mut data = Vec::new();
+            while data.len() < 4000 {
+                let len = 1 + rng.below(64);
+                data.extend((1..len).map(|_| b"ab;"[rng.below(3)]));
+                data.push(b'\n');
+            }
+            assert_eq!(
+                guarded_lines::<M, 4>(&mut pages, &data),
+                expected_lines(&data)
+            );
+        }
+    }
+
+    test_all_levels!(
+        #[cfg_attr(miri, ignore)]
+        test_delimiter_indexes,
+        check_delimiter_indexes
+    );
+}
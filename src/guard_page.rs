@@ -0,0 +1,71 @@
+//! Test helpers for checking that unchecked memory operations never read
+//! further than they're allowed to.
+
+use std::ptr::NonNull;
+
+/// Readable and writable pages followed by a PROT_NONE guard page,
+/// so that touching the byte after the last readable one segfaults.
+pub struct GuardedPages {
+    ptr: NonNull<u8>,
+    readable_size: usize,
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+impl GuardedPages {
+    pub fn new(readable_pages: usize) -> Self {
+        let readable_size = readable_pages * page_size();
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                readable_size + page_size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(ptr, libc::MAP_FAILED);
+
+        let guard = unsafe { ptr.add(readable_size) };
+        assert_eq!(
+            unsafe { libc::mprotect(guard, page_size(), libc::PROT_NONE) },
+            0
+        );
+
+        Self {
+            ptr: NonNull::new(ptr as *mut u8).unwrap(),
+            readable_size,
+        }
+    }
+
+    /// Returns the last `len` readable bytes, which end right at the guard page.
+    pub fn tail_mut(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.readable_size);
+        unsafe {
+            std::slice::from_raw_parts_mut(self.ptr.as_ptr().add(self.readable_size - len), len)
+        }
+    }
+
+    /// Copies `data` so that exactly `slack` readable bytes follow it
+    /// before the guard page, filling them with `fill`.
+    pub fn place(&mut self, data: &[u8], slack: usize, fill: u8) -> &[u8] {
+        let tail = self.tail_mut(data.len() + slack);
+        tail[..data.len()].copy_from_slice(data);
+        tail[data.len()..].fill(fill);
+        &tail[..data.len()]
+    }
+}
+
+impl Drop for GuardedPages {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(
+                self.ptr.as_ptr() as *mut libc::c_void,
+                self.readable_size + page_size(),
+            )
+        };
+    }
+}
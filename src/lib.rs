@@ -1,9 +1,22 @@
-#![feature(hint_prefetch)]
-#![feature(allocator_api)]
+// With the nightly feature, allocator-api2 re-exports the unstable std
+// Allocator trait, which MmapAllocator then implements.
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
 pub mod annotations;
+pub mod batched_lines;
 pub mod error;
+#[cfg(test)]
+mod guard_page;
 pub mod memops;
 pub mod mmap_allocator;
+pub mod perfect_hash;
+pub mod quoting;
 pub mod station_map;
+#[cfg(test)]
+mod temp_file;
+pub mod temperature_parser;
 pub mod temperature_summary;
+#[cfg(test)]
+mod test_data;
+#[cfg(target_os = "linux")]
+pub mod uring;
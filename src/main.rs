@@ -1,24 +1,50 @@
-#![feature(hint_prefetch)]
+// The batched loops index several per-line arrays in lockstep.
+#![allow(clippy::needless_range_loop)]
 
-use brc::memops::memchr64_unchecked;
+use brc::annotations::unlikely;
+use brc::batched_lines::{DontNeedBarrier, IterationControl, batched_process_lines, map_file};
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+use brc::memops::{Avx2, Avx512, Sse2};
+use brc::memops::{Memops, Scalar, SimdLevel, Swar};
+use brc::perfect_hash::PerfectStationMap;
+use brc::quoting::{opens_quote, unquote};
 use brc::station_map::StationMap;
 use brc::station_map::StationMapOptions;
-use brc::station_map::StationNameKey;
-use brc::station_map::StationNameKeyView;
-use brc::station_map::new_station_map;
-use cmov::Cmov;
-use memmap2::MmapOptions;
-use std::usize;
-use std::{cmp::Ordering, fmt::Display, fs::File, process::ExitCode};
+use brc::station_map::{DEFAULT_HASH_SEED, HashFallback, MAX_NAME_LEN, NameHash, new_station_map};
+use brc::temperature_parser::{Reading, TemperatureParser, TemperatureParserOptions};
+#[cfg(target_os = "linux")]
+use brc::uring::UringReader;
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    cmp::Ordering,
+    fmt::Display,
+    fs::File,
+    hash::{BuildHasher, RandomState},
+    process::ExitCode,
+    time::{Duration, Instant},
+};
 
 use brc::error::{BrcError, BrcResult};
+#[cfg(feature = "packed-summary")]
+use brc::temperature_summary::PackedTemperatureSummary as TemperatureSummary;
+#[cfg(not(feature = "packed-summary"))]
 use brc::temperature_summary::TemperatureSummary;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use itertools::Itertools;
+use memmap2::Mmap;
+
+#[cfg(test)]
+mod temp_file;
+// The library's tests use the rest of it.
+#[cfg(test)]
+#[allow(dead_code)]
+mod test_data;
 
 pub struct WeatherStation {
     name: String,
     summary: TemperatureSummary,
+    scale: u32,
 }
 
 impl PartialEq for WeatherStation {
@@ -41,254 +67,943 @@ impl Ord for WeatherStation {
     }
 }
 
-struct FloatAsIntEn1(i32);
+/// A fixed-point number with `scale` fractional digits.
+struct FixedPoint {
+    value: i64,
+    scale: u32,
+}
 
-impl Display for FloatAsIntEn1 {
+impl Display for FixedPoint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let a = self.0.abs() / 10;
-        let b = self.0.abs() % 10;
-        let sign = if self.0 < 0 { "-" } else { "" };
-        write!(f, "{}{}.{}", sign, a.abs(), b)
+        let unit = 10u64.pow(self.scale);
+        let a = self.value.unsigned_abs() / unit;
+        let b = self.value.unsigned_abs() % unit;
+        let sign = if self.value < 0 { "-" } else { "" };
+        if self.scale == 0 {
+            write!(f, "{}{}", sign, a)
+        } else {
+            write!(
+                f,
+                "{}{}.{:0width$}",
+                sign,
+                a,
+                b,
+                width = self.scale as usize
+            )
+        }
     }
 }
 
 impl Display for WeatherStation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}={}/{:.01}/{}",
-            self.name,
-            FloatAsIntEn1(self.summary.min()),
-            self.summary.avg(),
-            FloatAsIntEn1(self.summary.max())
-        )
+        let fixed = |value| FixedPoint {
+            value,
+            scale: self.scale,
+        };
+        if self.summary.count() == 0 {
+            write!(f, "{}=NaN/NaN/NaN", self.name)?;
+        } else {
+            write!(
+                f,
+                "{}={}/{}/{}",
+                self.name,
+                fixed(self.summary.min() as i64),
+                fixed(self.summary.avg()),
+                fixed(self.summary.max() as i64)
+            )?;
+        }
+        if self.summary.missing() != 0 {
+            write!(f, " ({} missing)", self.summary.missing())?;
+        }
+        Ok(())
     }
 }
 
-fn digit_to_i32(d: u8) -> i32 {
-    d.wrapping_sub(b'0') as i32
+// This is rarely called (10k times out of 1B rows),
+// so make sure it's outlined from the hot path.
+#[inline(never)]
+fn insert_temperature(m: &mut StationMap<TemperatureSummary>, k: &str, temp: i32) {
+    m.get_or_default(k).add_reading(temp)
 }
 
-/// Parses a float of the form ;[-][d]d.d from the end of a string.
-#[cfg_attr(feature = "profiled", inline(never))]
-fn parse_temperature(line: &[u8]) -> i32 {
-    let p = line.as_ptr().wrapping_add(line.len() - 1);
+/// Records a line the batched fast path can't handle, e.g. one with a
+/// missing reading or a quoted station name. Blank and comment lines
+/// are skipped if `args` allows them.
+///
+/// Fails if the line is malformed, or its station name is longer than
+/// `MAX_NAME_LEN`.
+#[inline(never)]
+fn record_line<const DELIM: u8, const DECIMAL: u8>(
+    m: &mut StationMap<TemperatureSummary>,
+    parser: &TemperatureParser<DELIM, DECIMAL>,
+    args: &Args,
+    line: &[u8],
+) -> BrcResult {
+    let malformed = || {
+        BrcError::new(format!(
+            "Malformed line \"{}\"",
+            String::from_utf8_lossy(line)
+        ))
+        .into()
+    };
+    if args.skip_blank_lines && line.iter().all(u8::is_ascii_whitespace) {
+        return Ok(());
+    }
+    if let Some(prefix) = &args.comment_prefix
+        && line.starts_with(prefix.as_bytes())
+    {
+        return Ok(());
+    }
 
-    // options: ;-99.9 or ;-9.9 or ;99.9 or ;9.9
-    //               ^        ^        ^       ^
-    let c0 = unsafe { *p };
+    let Some(delim_idx) = line.iter().rposition(|&c| c == DELIM) else {
+        return Err(malformed());
+    };
+    let reading = parser.parse(line, delim_idx);
 
-    // options: ;-99.9 or ;-9.9 or ;99.9 or ;9.9
-    //             ^        ^        ^       ^
-    let c1 = unsafe { *p.sub(2) };
+    let name = &line[..delim_idx];
+    let Some(name) = (if args.quoted_names {
+        unquote(name)
+    } else {
+        Some(Cow::Borrowed(name))
+    }) else {
+        return Err(malformed());
+    };
+    if name.len() > MAX_NAME_LEN {
+        return Err(BrcError::new(format!(
+            "Station name longer than {MAX_NAME_LEN} bytes in line \"{}\"",
+            String::from_utf8_lossy(line)
+        ))
+        .into());
+    }
+    let station = unsafe { std::str::from_utf8_unchecked(&name) };
 
-    // options: ;-99.9 or ;-9.9 or ;99.9 or ;9.9
-    //            ^        ^        ^       ^
-    let c2 = unsafe { *p.sub(3) };
+    match reading {
+        Reading::Value(temp) => insert_temperature(m, station, temp),
+        Reading::Missing => m.get_or_default(station).add_missing(),
+        Reading::Malformed => return Err(malformed()),
+    }
+    Ok(())
+}
 
-    // options: ;-99.9 or ;-9.9 or ;99.9 or ;9.9
-    //           ^        ^        ^       ^
-    let c3 = unsafe { *p.sub(4) };
+/// Records line with `record_line`, unless it's part of a quoted station
+/// name with line breaks in it. Those lines are collected in open_record,
+/// and recorded as one line once the name is closed.
+#[inline(never)]
+fn record_or_join_line<const DELIM: u8, const DECIMAL: u8>(
+    m: &mut StationMap<TemperatureSummary>,
+    parser: &TemperatureParser<DELIM, DECIMAL>,
+    args: &Args,
+    open_record: &mut Vec<u8>,
+    line: &[u8],
+) -> BrcResult {
+    if !open_record.is_empty() {
+        open_record.push(b'\n');
+    } else if !(args.quoted_names && opens_quote(line)) {
+        return record_line(m, parser, args, line);
+    }
+    open_record.extend_from_slice(line);
+    if opens_quote(open_record) {
+        return Ok(());
+    }
+    let record = std::mem::take(open_record);
+    record_line(m, parser, args, &record)
+}
 
-    let is_two_digits = (c2 == b';') | (c2 == b'-');
-    let is_negative = (c3 == b'-') | (c2 == b'-');
+/// A batch of lines with readings whose stations have been hashed and
+/// prefetched, waiting in a `PrefetchQueue` to be recorded.
+#[derive(Clone, Copy)]
+struct HashedBatch<'a, const N: usize> {
+    stations: [&'a str; N],
+    hashes: [u64; N],
+    temperatures: [i32; N],
+}
 
-    let mut hundreds = digit_to_i32(c2);
-    hundreds.cmovnz(&0, is_two_digits as u8);
+impl<const N: usize> HashedBatch<'_, N> {
+    const EMPTY: Self = HashedBatch {
+        stations: [""; N],
+        hashes: [0; N],
+        temperatures: [0; N],
+    };
+}
 
-    let mut result = 10 * (10 * hundreds + digit_to_i32(c1)) + digit_to_i32(c0);
-    let negative_result = -result;
-    result.cmovnz(&negative_result, is_negative as u8);
+const MAX_PREFETCH_DISTANCE: usize = 16;
 
-    result
+/// Delays recording each batch until `distance` more batches have been
+/// hashed, so that the slots prefetched for it have time to arrive in
+/// cache.
+struct PrefetchQueue<'a, const N: usize> {
+    batches: [HashedBatch<'a, N>; MAX_PREFETCH_DISTANCE],
+    distance: usize,
+    head: usize,
+    len: usize,
 }
 
-enum IterationControl {
-    Continue,
-}
+impl<'a, const N: usize> PrefetchQueue<'a, N> {
+    fn new(distance: usize) -> Self {
+        PrefetchQueue {
+            batches: [HashedBatch::EMPTY; MAX_PREFETCH_DISTANCE],
+            distance,
+            head: 0,
+            len: 0,
+        }
+    }
 
-#[inline(never)]
-fn drop_mmap_range(mmap: &memmap2::Mmap, start: usize, size: usize) -> BrcResult<()> {
-    unsafe {
-        mmap.unchecked_advise_range(memmap2::UncheckedAdvice::DontNeed, start, size)?;
+    /// Queues batch, returning the batch queued `distance` batches before
+    /// it, if any.
+    #[inline(always)]
+    fn push(&mut self, batch: HashedBatch<'a, N>) -> Option<HashedBatch<'a, N>> {
+        if self.len < self.distance {
+            self.batches[self.len] = batch;
+            self.len += 1;
+            return None;
+        }
+        if self.distance == 0 {
+            return Some(batch);
+        }
+        let oldest = std::mem::replace(&mut self.batches[self.head], batch);
+        self.head += 1;
+        if self.head == self.distance {
+            self.head = 0;
+        }
+        Some(oldest)
     }
-    Ok(())
-}
 
-#[cfg_attr(feature = "profiled", inline(never))]
-fn batched_process_lines<const N: usize, FN, F1>(
-    file: File,
-    mut batch_callback: FN,
-    mut single_callback: F1,
-) -> BrcResult<()>
-where
-    FN: FnMut(&[&[u8]]) -> IterationControl,
-    F1: FnMut(&[u8]) -> IterationControl,
-{
-    let mmap = unsafe { MmapOptions::new().map(&file)? };
-    mmap.advise(memmap2::Advice::Sequential)?;
-    mmap.advise(memmap2::Advice::WillNeed)?;
-
-    let mut cursor: usize = 0;
-
-    // Handle the boundary condition of the last bytes separately.
-    let mmap_boundary = mmap.len() - 256usize;
-
-    // Every 256MiB, we madvise DONTNEED on the pages we've already processed
-    // so that resident memory stays small.
-    //
-    // This is actually a tiny bit of a performance hit,
-    // but it stops htop from reporting GiBs of memory usage.
-    const DONTNEED_SIZE: usize = 256usize << 20;
-    let mut dontneed_barrier = DONTNEED_SIZE;
-
-    while cursor < mmap_boundary {
-        let mut slices: [&[u8]; N] = [&[]; N];
+    /// Empties the queue, oldest batch first.
+    fn drain(&mut self) -> impl Iterator<Item = HashedBatch<'a, N>> {
+        let (head, len) = (self.head, self.len);
+        self.head = 0;
+        self.len = 0;
+        (0..len).map(move |i| self.batches[(head + i) % self.distance])
+    }
+}
 
+/// Adds the readings of a batch to their stations, looking them up in
+/// perfect if there is one, and inserting them into m otherwise.
+#[inline(always)]
+fn record_batch<M: Memops, const N: usize>(
+    m: &mut StationMap<TemperatureSummary>,
+    perfect: Option<&PerfectStationMap<TemperatureSummary>>,
+    batch: &HashedBatch<N>,
+) {
+    // Stations missing from the sample aren't in the perfect hash, and
+    // are inserted into m below.
+    let mut entries: [Option<&TemperatureSummary>; N] = [None; N];
+    if let Some(perfect) = perfect {
+        for i in 0..N {
+            entries[i] = unsafe { perfect.get_with::<M>(batch.hashes[i], batch.stations[i]) };
+        }
+    } else {
         for i in 0..N {
-            let newline_idx = unsafe { memchr64_unchecked::<b'\n'>(&mmap.get_unchecked(cursor..)) };
-            slices[i] = unsafe { &mmap.get_unchecked(cursor..cursor + newline_idx) };
-            cursor += newline_idx + 1;
+            entries[i] = unsafe { m.get_with::<M>(batch.hashes[i], batch.stations[i]) };
         }
+    }
+
+    let mut found = [false; N];
+    for i in 0..N {
+        found[i] = entries[i].is_some();
+    }
 
-        batch_callback(&slices);
+    for i in 0..N {
+        if let Some(e) = entries[i] {
+            e.add_reading(batch.temperatures[i]);
+        }
+    }
 
-        // This ensures we don't keep too much data in RAM.
-        if cursor >= dontneed_barrier {
-            drop_mmap_range(&mmap, dontneed_barrier - DONTNEED_SIZE, DONTNEED_SIZE)?;
-            dontneed_barrier += DONTNEED_SIZE;
+    for i in 0..N {
+        if !found[i] {
+            insert_temperature(m, batch.stations[i], batch.temperatures[i]);
         }
     }
+}
 
-    // Deal with boundary condition at end of mmap'd region.
-    while cursor < mmap.len() {
-        let remaining = unsafe { mmap.get_unchecked(cursor..) };
-        let mut data = [0; 64];
-        let remaining_with_safe_boundary = &mut data[..remaining.len().min(64)];
-        (remaining_with_safe_boundary).copy_from_slice(&remaining[..remaining.len().min(64)]);
+type WeatherStations = std::vec::IntoIter<WeatherStation>;
 
-        let newline_idx = unsafe { memchr64_unchecked::<b'\n'>(remaining_with_safe_boundary) };
-        single_callback(unsafe { remaining_with_safe_boundary.get_unchecked(..newline_idx) });
-        cursor += newline_idx + 1;
+fn temperature_reading_summaries(args: &Args) -> BrcResult<WeatherStations> {
+    match args.decimal_separator {
+        DecimalSeparator::Dot => summaries_with_decimal_separator::<b'.'>(args),
+        DecimalSeparator::Comma => summaries_with_decimal_separator::<b','>(args),
     }
+}
 
-    Ok(())
+fn summaries_with_decimal_separator<const DECIMAL: u8>(args: &Args) -> BrcResult<WeatherStations> {
+    match args.delimiter {
+        Delimiter::Semicolon => summaries_with_format::<b';', DECIMAL>(args),
+        Delimiter::Tab => summaries_with_format::<b'\t', DECIMAL>(args),
+        Delimiter::Comma => summaries_with_format::<b',', DECIMAL>(args),
+        Delimiter::Pipe => summaries_with_format::<b'|', DECIMAL>(args),
+    }
 }
 
-// This is rarely called (10k times out of 1B rows),
-// so make sure it's outlined from the hot path.
+fn simd_level(args: &Args) -> BrcResult<SimdLevel> {
+    let Some(level) = args.simd.level() else {
+        return Ok(SimdLevel::detect());
+    };
+    if !level.is_supported() {
+        return Err(BrcError::new(format!("This CPU does not support {level:?}")).into());
+    }
+    Ok(level)
+}
+
+/// Prints how well the station name hash did to stderr, for `--stats`.
 #[inline(never)]
-fn insert_temperature(m: &mut StationMap<TemperatureSummary>, k: &str, temp: i32) {
-    m.entry(StationNameKey::new(k))
-        .or_default()
-        .add_reading(temp)
+fn print_stats(
+    temperatures: &StationMap<TemperatureSummary>,
+    perfect: Option<&PerfectStationMap<TemperatureSummary>>,
+    hash_fallback: &HashFallback,
+    batch_width: usize,
+) {
+    let probe_lengths = temperatures.probe_lengths();
+    eprintln!(
+        "stations: {}, load factor: {:.3}",
+        temperatures.len(),
+        temperatures.load_factor()
+    );
+    eprintln!(
+        "probe length: {:.3} average ({:.3} with a uniform hash), {} maximum",
+        probe_lengths.avg,
+        temperatures.uniform_probe_length(),
+        probe_lengths.max
+    );
+    match hash_fallback.fell_back_after() {
+        Some(lookups) => eprintln!("hash fallback: after {lookups} lookups"),
+        None => eprintln!("hash fallback: no"),
+    }
+    match perfect {
+        Some(perfect) => eprintln!("perfect hash: {} sampled stations", perfect.len()),
+        None => eprintln!("perfect hash: no"),
+    }
+    eprintln!("batch width: {batch_width}");
+}
+
+/// Recognizes the lines the batched fast path handles.
+#[derive(Clone, Copy)]
+struct PlainLines {
+    quote_byte: u16,
+    comment_byte: u16,
+}
+
+impl PlainLines {
+    // Lines starting with either byte go to the slow path. NO_BYTE is
+    // never equal to a byte, so disabled options cost one compare.
+    const NO_BYTE: u16 = 0x100;
+
+    fn new(args: &Args) -> Self {
+        PlainLines {
+            quote_byte: if args.quoted_names {
+                b'"' as u16
+            } else {
+                Self::NO_BYTE
+            },
+            comment_byte: args
+                .comment_prefix
+                .as_ref()
+                .map_or(Self::NO_BYTE, |prefix| prefix.as_bytes()[0] as u16),
+        }
+    }
+
+    /// Blank lines have no delimiter, and comments and quoted names are
+    /// recognized by their first byte. Names too long for the station map
+    /// are left for `record_line` to reject.
+    ///
+    /// # Safety
+    ///
+    /// The line must be followed by at least one readable byte if it's
+    /// empty, as it is in a batch.
+    #[inline(always)]
+    unsafe fn accepts(self, line: &[u8], delim_idx: usize) -> bool {
+        let first = unsafe { *line.as_ptr() } as u16;
+        (delim_idx < line.len())
+            & (delim_idx <= MAX_NAME_LEN)
+            & (first != self.quote_byte)
+            & (first != self.comment_byte)
+    }
+}
+
+/// Builds a `PerfectStationMap` of the stations in the plain lines in the
+/// first `args.sample_bytes` of data, or returns None if there are none.
+///
+/// A line cut off by the end of the sample may add a station that isn't in
+/// the data, which only costs a slot that ends up with no readings.
+#[inline(always)]
+fn sample_stations<M: Memops, const N: usize, const DELIM: u8>(
+    data: &[u8],
+    args: &Args,
+    name_hash: &NameHash,
+    plain: PlainLines,
+) -> BrcResult<Option<PerfectStationMap<TemperatureSummary>>> {
+    let mut stations = new_station_map::<()>(&StationMapOptions {
+        request_hugepage: false,
+        capacity: 12_000,
+        name_hash: name_hash.clone(),
+    });
+    let mut tail_buffer = Vec::new();
+    batched_process_lines::<M, N, DELIM, _, _>(
+        &data[..data.len().min(args.sample_bytes)],
+        args.skip_lines,
+        &mut tail_buffer,
+        |lines: &[&[u8]], delim_indexes: &[usize]| {
+            for (line, &delim_idx) in lines.iter().zip(delim_indexes) {
+                if unsafe { plain.accepts(line, delim_idx) } {
+                    stations.get_or_default(unsafe {
+                        std::str::from_utf8_unchecked(line.get_unchecked(..delim_idx))
+                    });
+                }
+            }
+            IterationControl::Continue
+        },
+        |_| Ok(()),
+    )?;
+    let names: Vec<&str> = stations.iter().map(|(name, _)| name).collect();
+    Ok(PerfectStationMap::new(&names, name_hash.clone()))
+}
+
+/// Returns the seed for the station name hash. Hardened runs get one from
+/// the OS, through std's RandomState.
+fn hash_seed(args: &Args) -> u64 {
+    match args.hash_seed {
+        Some(seed) => seed,
+        None if args.harden_hash => RandomState::new().hash_one(0u64),
+        None => DEFAULT_HASH_SEED,
+    }
+}
+
+fn summaries_with_format<const DELIM: u8, const DECIMAL: u8>(
+    args: &Args,
+) -> BrcResult<WeatherStations> {
+    match simd_level(args)? {
+        #[cfg(all(target_arch = "x86_64", not(miri)))]
+        SimdLevel::Avx512 => unsafe { summaries_avx512::<DELIM, DECIMAL>(args) },
+        #[cfg(all(target_arch = "x86_64", not(miri)))]
+        SimdLevel::Avx2 => unsafe { summaries_avx2::<DELIM, DECIMAL>(args) },
+        #[cfg(all(target_arch = "x86_64", not(miri)))]
+        SimdLevel::Sse2 => summaries::<Sse2, DELIM, DECIMAL>(args),
+        SimdLevel::Swar => summaries::<Swar, DELIM, DECIMAL>(args),
+        SimdLevel::Scalar => summaries::<Scalar, DELIM, DECIMAL>(args),
+    }
+}
+
+// The hot loop is inlined into these, so all of it is compiled with the
+// target feature and that level's memops can be inlined into it.
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+#[target_feature(enable = "avx2")]
+fn summaries_avx2<const DELIM: u8, const DECIMAL: u8>(args: &Args) -> BrcResult<WeatherStations> {
+    summaries::<Avx2, DELIM, DECIMAL>(args)
+}
+
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+#[target_feature(enable = "avx512bw")]
+fn summaries_avx512<const DELIM: u8, const DECIMAL: u8>(args: &Args) -> BrcResult<WeatherStations> {
+    summaries::<Avx512, DELIM, DECIMAL>(args)
 }
 
 #[cfg_attr(feature = "profiled", inline(never))]
-fn temperature_reading_summaries(args: &Args) -> BrcResult<impl Iterator<Item = WeatherStation>> {
+#[cfg_attr(not(feature = "profiled"), inline(always))]
+fn summaries<M: Memops, const DELIM: u8, const DECIMAL: u8>(
+    args: &Args,
+) -> BrcResult<WeatherStations> {
     let file = File::open(&args.input)
         .map_err(|err| BrcError::new(format!("Failed to open {}: {err}", args.input)))?;
 
+    if args.comment_prefix.as_deref() == Some("") {
+        return Err(BrcError::new("Comment prefix must not be empty".to_owned()).into());
+    }
+
+    // Sampling and calibration only read the start of the input, so that's
+    // all that's read up front when the input isn't mapped.
+    let head_len = match args.batch_width.width() {
+        Some(_) => 0,
+        None => args.calibration_bytes,
+    }
+    .max(if args.perfect_hash {
+        args.sample_bytes
+    } else {
+        0
+    });
+    let (mmap, head) = match args.io {
+        Io::Mmap => (Some(map_file(&file)?), Vec::new()),
+        #[cfg(target_os = "linux")]
+        Io::Uring => (None, read_head(args, head_len)?),
+    };
+    let data: &[u8] = mmap.as_deref().unwrap_or(&head);
+    let input_len = file.metadata()?.len() as usize;
+
+    let name_hash = NameHash::Fast(hash_seed(args));
+    let mut perfect = if args.perfect_hash {
+        sample_stations::<M, DEFAULT_BATCH_WIDTH, DELIM>(
+            data,
+            args,
+            &name_hash,
+            PlainLines::new(args),
+        )?
+    } else {
+        None
+    };
+
+    // Calibration runs go through the same call as the real one, so that
+    // each width's hot loop is only inlined here once.
+    let mut calibration = match args.batch_width.width() {
+        Some(width) => BatchWidthCalibration::fixed(width),
+        None => BatchWidthCalibration::new(data, input_len, args.calibration_bytes),
+    };
+    loop {
+        let trial = calibration.next_trial();
+        let pass = Pass {
+            args,
+            mmap: mmap.as_ref(),
+            data,
+            len: trial.map_or(data.len(), |_| calibration.len),
+            io: if trial.is_none() { args.io } else { Io::Mmap },
+            name_hash: &name_hash,
+            perfect: perfect.as_ref(),
+            stats: trial.is_none() && args.stats,
+            trial: trial.is_some(),
+        };
+        let width = trial.unwrap_or_else(|| calibration.width());
+        if pass.stats {
+            calibration.print_stats();
+        }
+        let start = Instant::now();
+        let summaries = summaries_with_width::<M, DELIM, DECIMAL>(width, &pass)?;
+        if trial.is_none() {
+            return Ok(summaries);
+        }
+        calibration.record(start.elapsed());
+        if let Some(perfect) = &mut perfect {
+            perfect.reset_values();
+        }
+    }
+}
+
+/// A pass over the input.
+struct Pass<'a> {
+    args: &'a Args,
+    /// The input's mapping, if `--io mmap` mapped it.
+    mmap: Option<&'a Mmap>,
+    /// The mapping's bytes, or the head of the input read for sampling and
+    /// calibration when it isn't mapped.
+    data: &'a [u8],
+    /// The number of bytes at the start of data to read.
+    len: usize,
+    /// How to read them. Only a full pass reads the input another way.
+    io: Io,
+    name_hash: &'a NameHash,
+    perfect: Option<&'a PerfectStationMap<TemperatureSummary>>,
+    /// Whether to print `--stats` at the end.
+    stats: bool,
+    /// Whether this is a calibration trial, which only reads part of the
+    /// input.
+    trial: bool,
+}
+
+/// The batch widths `--batch-width` can pick.
+const BATCH_WIDTHS: [usize; 5] = [1, 2, 4, 8, 16];
+const DEFAULT_BATCH_WIDTH: usize = 4;
+
+#[inline(always)]
+fn summaries_with_width<M: Memops, const DELIM: u8, const DECIMAL: u8>(
+    batch_width: usize,
+    pass: &Pass,
+) -> BrcResult<WeatherStations> {
+    match batch_width {
+        1 => summaries_in_batches::<M, 1, DELIM, DECIMAL>(pass),
+        2 => summaries_in_batches::<M, 2, DELIM, DECIMAL>(pass),
+        4 => summaries_in_batches::<M, 4, DELIM, DECIMAL>(pass),
+        8 => summaries_in_batches::<M, 8, DELIM, DECIMAL>(pass),
+        16 => summaries_in_batches::<M, 16, DELIM, DECIMAL>(pass),
+        _ => unreachable!("{batch_width} is not one of {BATCH_WIDTHS:?}"),
+    }
+}
+
+/// Picks the batch width for `--batch-width auto`, by timing passes over
+/// the first `--calibration-bytes` of the input with each width, best of
+/// `ROUNDS`.
+///
+/// Inputs less than `MIN_INPUT_RATIO` times that long aren't worth
+/// calibrating for, and get `DEFAULT_BATCH_WIDTH`.
+struct BatchWidthCalibration {
+    /// The number of bytes the trials read, ending on a whole line, which
+    /// the tail then handles like the last line of the input.
+    len: usize,
+    trials: usize,
+    best: [Duration; BATCH_WIDTHS.len()],
+    /// The width to use without any trials.
+    default: usize,
+}
+
+impl BatchWidthCalibration {
+    const ROUNDS: usize = 2;
+    const MIN_INPUT_RATIO: usize = 256;
+
+    /// Calibrates on the start of data, the first bytes of an input
+    /// `input_len` bytes long.
+    fn new(data: &[u8], input_len: usize, calibration_bytes: usize) -> Self {
+        let newline_idx = data
+            .get(..calibration_bytes)
+            .filter(|_| input_len / Self::MIN_INPUT_RATIO >= calibration_bytes)
+            .and_then(|head| head.iter().rposition(|&c| c == b'\n'));
+        match newline_idx {
+            Some(newline_idx) => BatchWidthCalibration {
+                len: newline_idx + 1,
+                trials: 0,
+                best: [Duration::MAX; BATCH_WIDTHS.len()],
+                default: DEFAULT_BATCH_WIDTH,
+            },
+            None => Self::fixed(DEFAULT_BATCH_WIDTH),
+        }
+    }
+
+    /// Always picks width, without any trials.
+    fn fixed(width: usize) -> Self {
+        BatchWidthCalibration {
+            len: 0,
+            trials: Self::ROUNDS * BATCH_WIDTHS.len(),
+            best: [Duration::MAX; BATCH_WIDTHS.len()],
+            default: width,
+        }
+    }
+
+    /// Returns the width to time next, or None once they've all been timed.
+    fn next_trial(&self) -> Option<usize> {
+        (self.trials < Self::ROUNDS * BATCH_WIDTHS.len())
+            .then(|| BATCH_WIDTHS[self.trials % BATCH_WIDTHS.len()])
+    }
+
+    /// Records how long the trial of `next_trial`'s width took.
+    fn record(&mut self, elapsed: Duration) {
+        let best = &mut self.best[self.trials % BATCH_WIDTHS.len()];
+        *best = (*best).min(elapsed);
+        self.trials += 1;
+    }
+
+    /// The fastest width, once the trials are done.
+    fn width(&self) -> usize {
+        (0..BATCH_WIDTHS.len())
+            .filter(|&i| self.best[i] != Duration::MAX)
+            .min_by_key(|&i| self.best[i])
+            .map_or(self.default, |i| BATCH_WIDTHS[i])
+    }
+
+    fn print_stats(&self) {
+        for (width, best) in BATCH_WIDTHS.iter().zip(&self.best) {
+            if *best != Duration::MAX {
+                eprintln!("batch width {width} calibration: {best:?}");
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "profiled", inline(never))]
+#[cfg_attr(not(feature = "profiled"), inline(always))]
+fn summaries_in_batches<M: Memops, const N: usize, const DELIM: u8, const DECIMAL: u8>(
+    pass: &Pass,
+) -> BrcResult<WeatherStations> {
+    let args = pass.args;
+    let perfect = pass.perfect;
     let mut temperatures_batch = new_station_map::<TemperatureSummary>(&StationMapOptions {
         request_hugepage: args.use_hugepages,
         capacity: 12_000,
+        name_hash: pass.name_hash.clone(),
     });
-    let mut temperatures_single = new_station_map::<TemperatureSummary>(&StationMapOptions {
-        request_hugepage: args.use_hugepages,
-        capacity: 100,
+    let mut hash_fallback = HashFallback::new(args.hash_fallback_threshold);
+
+    let parser = TemperatureParser::<DELIM, DECIMAL>::new(&TemperatureParserOptions {
+        scale: args.scale,
+        missing_tokens: &args.missing_tokens,
+    })?;
+    // The error of the first line record_line failed on.
+    let mut line_result: Cell<BrcResult> = Cell::new(Ok(()));
+    // The lines so far of a quoted station name that goes on past them.
+    let mut open_record = Vec::new();
+    let plain = PlainLines::new(args);
+
+    let mut tail_buffer = Vec::new();
+    let prefetch_distance = args.prefetch_distance as usize;
+    let mut dontneed_barrier = DontNeedBarrier::new(match pass.io {
+        Io::Mmap => pass.mmap,
+        #[cfg(target_os = "linux")]
+        Io::Uring => None,
     });
+    let mut chunks = match pass.io {
+        Io::Mmap => Chunks::Mapped(Some((&pass.data[..pass.len], args.skip_lines))),
+        #[cfg(target_os = "linux")]
+        Io::Uring => Chunks::Uring(Box::new(UringReader::open(
+            &args.input,
+            args.skip_lines,
+            args.read_size,
+        )?)),
+    };
 
-    const N: usize = 4;
-    batched_process_lines::<N, _, _>(
-        file,
-        |lines: &[&[u8]]| {
-            let mut delim_indexes = [0usize; N];
-            for i in 0..N {
-                delim_indexes[i] = unsafe { memchr64_unchecked::<b';'>(lines[i]) };
-            }
+    // Every chunk goes through this one call, so that the hot loop is only
+    // inlined once. Queued batches hold on to lines of the chunk, so the
+    // queue doesn't outlive it.
+    while let Some((chunk, skip_lines)) = chunks.next()? {
+        let mut queue = PrefetchQueue::<N>::new(prefetch_distance);
+        batched_process_lines::<M, N, DELIM, _, _>(
+            chunk,
+            skip_lines,
+            &mut tail_buffer,
+            // Otherwise this isn't inlined into summaries_avx2, and gets
+            // compiled without AVX2.
+            #[inline(always)]
+            |lines, delim_indexes| {
+                let record_lines_slow =
+                    |temperatures_batch: &mut StationMap<TemperatureSummary>,
+                     open_record: &mut Vec<u8>| {
+                        for line in lines {
+                            if let Err(err) = record_or_join_line(
+                                temperatures_batch,
+                                &parser,
+                                args,
+                                open_record,
+                                line,
+                            ) {
+                                line_result.set(Err(err));
+                                return IterationControl::Break;
+                            }
+                        }
+                        IterationControl::Continue
+                    };
 
-            let mut station_temperatures = [0i32; N];
-            for i in 0..N {
-                station_temperatures[i] = parse_temperature(lines[i]);
-            }
+                // Only the last batch of a chunk can be short.
+                if unlikely(lines.len() < N) {
+                    return record_lines_slow(&mut temperatures_batch, &mut open_record);
+                }
 
-            let mut stations = [""; N];
-            for i in 0..N {
-                stations[i] = unsafe {
-                    std::str::from_utf8_unchecked(lines[i].get_unchecked(..delim_indexes[i]))
-                };
-            }
+                // Lines that go on a quoted name are never plain.
+                let mut all_plain = open_record.is_empty();
+                for i in 0..N {
+                    all_plain &= unsafe { plain.accepts(lines[i], delim_indexes[i]) };
+                }
+                if unlikely(!all_plain) {
+                    return record_lines_slow(&mut temperatures_batch, &mut open_record);
+                }
 
-            let mut hashes = [0u64; N];
-            for i in 0..N {
-                hashes[i] = StationNameKeyView::new(stations[i]).hash_u64();
-            }
+                let mut readings = [Reading::Missing; N];
+                for i in 0..N {
+                    readings[i] = parser.parse(lines[i], delim_indexes[i]);
+                }
 
-            let mut entries: [Option<(&StationNameKey, &TemperatureSummary)>; N] = [None; N];
-            for i in 0..N {
-                entries[i] = temperatures_batch.raw_entry().from_hash(hashes[i], |k| {
-                    k.view() == StationNameKeyView::new(stations[i])
-                });
-            }
+                let mut station_temperatures = [0i32; N];
+                let mut all_values = true;
+                for i in 0..N {
+                    match readings[i] {
+                        Reading::Value(temp) => station_temperatures[i] = temp,
+                        _ => all_values = false,
+                    }
+                }
 
-            let mut found = [false; N];
-            for i in 0..N {
-                found[i] = entries[i].is_some();
-            }
+                let mut stations = [""; N];
+                for i in 0..N {
+                    stations[i] = unsafe {
+                        std::str::from_utf8_unchecked(lines[i].get_unchecked(..delim_indexes[i]))
+                    };
+                }
 
-            for i in 0..N {
-                if let Some(e) = entries[i] {
-                    e.1.add_reading(station_temperatures[i]);
+                if unlikely(!all_values) {
+                    return record_lines_slow(&mut temperatures_batch, &mut open_record);
                 }
-            }
 
-            for i in 0..N {
-                if !found[i] {
-                    insert_temperature(
-                        &mut temperatures_batch,
-                        stations[i],
-                        station_temperatures[i],
-                    );
+                let name_hash = match perfect {
+                    Some(perfect) => perfect.name_hash(),
+                    None => temperatures_batch.name_hash(),
+                };
+                let mut hashes = [0u64; N];
+                for i in 0..N {
+                    hashes[i] = unsafe { name_hash.hash_with::<M>(stations[i].as_bytes()) };
                 }
-            }
 
-            IterationControl::Continue
-        },
-        |line| {
-            let delim_idx = unsafe { memchr64_unchecked::<b';'>(line) };
-            let temperature = parse_temperature(line);
-            let station = unsafe { std::str::from_utf8_unchecked(line.get_unchecked(..delim_idx)) };
+                if prefetch_distance != 0 {
+                    if let Some(perfect) = perfect {
+                        for i in 0..N {
+                            perfect.prefetch(hashes[i]);
+                        }
+                    } else {
+                        for i in 0..N {
+                            temperatures_batch.prefetch(hashes[i]);
+                        }
+                    }
+                }
 
-            if let Some(v) = temperatures_single.get_mut(StationNameKeyView::new(station)) {
-                v.add_reading(temperature);
-            } else {
-                temperatures_single.insert(
-                    StationNameKey::new(station),
-                    TemperatureSummary::of(temperature),
-                );
-            }
+                let batch = HashedBatch {
+                    stations,
+                    hashes,
+                    temperatures: station_temperatures,
+                };
+                if let Some(batch) = queue.push(batch) {
+                    record_batch::<M, N>(&mut temperatures_batch, perfect, &batch);
+                }
 
-            IterationControl::Continue
-        },
-    )?;
+                if unlikely(hash_fallback.should_fall_back(&temperatures_batch, N)) {
+                    // Queued batches were hashed with the old hash.
+                    for batch in queue.drain() {
+                        record_batch::<M, N>(&mut temperatures_batch, perfect, &batch);
+                    }
+                    // Keys from the OS, so this can't be collided on purpose.
+                    temperatures_batch.set_name_hash(NameHash::Strong(RandomState::new()));
+                }
 
-    for (k, v_single) in temperatures_single.into_iter() {
-        if let Some(v_batch) = temperatures_batch.get_mut(k.view()) {
-            v_batch.add(&v_single);
-        } else {
-            temperatures_batch.insert(k, v_single);
+                IterationControl::Continue
+            },
+            |cursor| dontneed_barrier.advance(cursor),
+        )?;
+        for batch in queue.drain() {
+            record_batch::<M, N>(&mut temperatures_batch, perfect, &batch);
+        }
+        if line_result.get_mut().is_err() {
+            break;
+        }
+    }
+    line_result.into_inner()?;
+    // A calibration trial may stop in the middle of a quoted name.
+    if !open_record.is_empty() && !pass.trial {
+        return Err(BrcError::new(format!(
+            "Unclosed quoted station name in \"{}\"",
+            String::from_utf8_lossy(&open_record)
+        ))
+        .into());
+    }
+
+    if let Some(perfect) = perfect {
+        for (name, summary) in perfect.iter() {
+            // Sampled stations may only have had lines off the fast path.
+            if summary.count() != 0 {
+                temperatures_batch.get_or_default(name).add(summary);
+            }
         }
     }
 
+    if pass.stats {
+        print_stats(&temperatures_batch, perfect, &hash_fallback, N);
+    }
+
     Ok(temperatures_batch
         .into_iter()
-        .map(|(station, summary)| WeatherStation {
-            name: station.into(),
+        .map(|(name, summary)| WeatherStation {
+            name,
             summary,
+            scale: parser.scale(),
         })
         .sorted_unstable())
 }
 
+/// Reads whole lines from the start of the input until there are at least
+/// `len` bytes of them, or the input ends, for when it isn't mapped.
+#[cfg(target_os = "linux")]
+fn read_head(args: &Args, len: usize) -> BrcResult<Vec<u8>> {
+    let mut head = Vec::new();
+    if len == 0 {
+        return Ok(head);
+    }
+    // Header lines are left in, as they are at the start of a mapping.
+    let mut reader = UringReader::open(&args.input, 0, args.read_size)?;
+    while head.len() < len
+        && let Some(chunk) = reader.next_chunk()?
+    {
+        head.extend_from_slice(chunk);
+    }
+    Ok(head)
+}
+
+/// Where `summaries_in_batches` reads runs of whole lines from.
+enum Chunks<'a> {
+    /// The first bytes of the input in memory as one chunk, with the
+    /// number of header lines to skip at its start.
+    Mapped(Option<(&'a [u8], usize)>),
+    /// Chunks read with io_uring, which skips header lines itself.
+    #[cfg(target_os = "linux")]
+    Uring(Box<UringReader>),
+}
+
+impl Chunks<'_> {
+    fn next(&mut self) -> BrcResult<Option<(&[u8], usize)>> {
+        match self {
+            Chunks::Mapped(chunk) => Ok(chunk.take()),
+            #[cfg(target_os = "linux")]
+            Chunks::Uring(reader) => Ok(reader.next_chunk()?.map(|chunk| (chunk, 0))),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Io {
+    Mmap,
+    #[cfg(target_os = "linux")]
+    Uring,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Delimiter {
+    Semicolon,
+    Tab,
+    Comma,
+    Pipe,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DecimalSeparator {
+    Dot,
+    Comma,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Simd {
+    Auto,
+    #[cfg(all(target_arch = "x86_64", not(miri)))]
+    Avx512,
+    #[cfg(all(target_arch = "x86_64", not(miri)))]
+    Avx2,
+    #[cfg(all(target_arch = "x86_64", not(miri)))]
+    Sse2,
+    Swar,
+    Scalar,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum BatchWidth {
+    Auto,
+    #[value(name = "1")]
+    W1,
+    #[value(name = "2")]
+    W2,
+    #[value(name = "4")]
+    W4,
+    #[value(name = "8")]
+    W8,
+    #[value(name = "16")]
+    W16,
+}
+
+impl BatchWidth {
+    /// Returns the width to use, or None to calibrate it.
+    fn width(self) -> Option<usize> {
+        match self {
+            BatchWidth::Auto => None,
+            BatchWidth::W1 => Some(1),
+            BatchWidth::W2 => Some(2),
+            BatchWidth::W4 => Some(4),
+            BatchWidth::W8 => Some(8),
+            BatchWidth::W16 => Some(16),
+        }
+    }
+}
+
+impl Simd {
+    /// Returns the level to force, or None to detect it.
+    fn level(self) -> Option<SimdLevel> {
+        match self {
+            Simd::Auto => None,
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            Simd::Avx512 => Some(SimdLevel::Avx512),
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            Simd::Avx2 => Some(SimdLevel::Avx2),
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            Simd::Sse2 => Some(SimdLevel::Sse2),
+            Simd::Swar => Some(SimdLevel::Swar),
+            Simd::Scalar => Some(SimdLevel::Scalar),
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(long, default_value = "measurements.txt")]
@@ -296,18 +1011,119 @@ struct Args {
 
     #[arg(long, default_value = "true", value_parser = clap::builder::BoolishValueParser::new())]
     use_hugepages: bool,
+
+    /// Number of fractional digits to keep for each reading.
+    #[arg(long, default_value_t = 1)]
+    scale: u32,
+
+    /// Temperature fields that mark a reading as missing. May be repeated.
+    #[arg(long = "missing-token", num_args = 0.., default_values = ["", "NaN", "null"])]
+    missing_tokens: Vec<String>,
+
+    /// Character separating the station name from the temperature.
+    #[arg(long, value_enum, default_value_t = Delimiter::Semicolon)]
+    delimiter: Delimiter,
+
+    /// Character separating the integer and fractional digits.
+    #[arg(long, value_enum, default_value_t = DecimalSeparator::Dot)]
+    decimal_separator: DecimalSeparator,
+
+    /// Treat station names wrapped in double quotes as CSV-style quoted
+    /// fields, with "" standing for a literal quote. Quoted names may
+    /// contain the delimiter, quotes and line breaks, but like any other
+    /// name must be at most 56 bytes long once unquoted.
+    #[arg(long)]
+    quoted_names: bool,
+
+    /// Number of header lines to skip at the start of the input.
+    #[arg(long, default_value_t = 0)]
+    skip_lines: usize,
+
+    /// Skip lines starting with this prefix, e.g. "#".
+    #[arg(long)]
+    comment_prefix: Option<String>,
+
+    /// Skip lines that are empty or only contain whitespace.
+    #[arg(long)]
+    skip_blank_lines: bool,
+
+    /// Instruction set to use, instead of the fastest one the CPU supports.
+    #[arg(long, value_enum, default_value_t = Simd::Auto)]
+    simd: Simd,
+
+    /// Seed the station name hash from the OS, so that an input can't be
+    /// crafted to make station names collide.
+    #[arg(long)]
+    harden_hash: bool,
+
+    /// Seed for the station name hash, e.g. to reproduce a hardened run.
+    #[arg(long, conflicts_with = "harden_hash")]
+    hash_seed: Option<u64>,
+
+    /// Switch to SipHash if finding a station reads at least this many
+    /// more slots of the hash table on average than it would with a
+    /// uniformly random hash.
+    #[arg(long, default_value_t = 0.5)]
+    hash_fallback_threshold: f64,
+
+    /// Collect the station names in the first --sample-bytes of the input,
+    /// then look stations up in a minimal perfect hash of them. Stations
+    /// that weren't sampled still go in the hash table.
+    #[arg(long)]
+    perfect_hash: bool,
+
+    /// Number of bytes at the start of the input to sample stations from,
+    /// for --perfect-hash.
+    #[arg(long, default_value_t = 4 << 20)]
+    sample_bytes: usize,
+
+    /// Number of batches of lines to hash and prefetch hash table slots
+    /// for before looking up their stations, or 0 to not prefetch.
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(..=MAX_PREFETCH_DISTANCE as i64))]
+    prefetch_distance: u8,
+
+    /// Number of lines looked up together in the hot loop. auto times each
+    /// width on the first --calibration-bytes of the input and picks the
+    /// fastest.
+    #[arg(long, value_enum, default_value_t = BatchWidth::W4)]
+    batch_width: BatchWidth,
+
+    /// Number of bytes at the start of the input to time each batch width
+    /// on, for --batch-width auto. Inputs less than 256 times as long use
+    /// the default width.
+    #[arg(long, default_value_t = 1 << 20)]
+    calibration_bytes: usize,
+
+    /// Print the hash table's load factor, average and maximum probe
+    /// length, and whether it fell back to SipHash, to stderr. The probe
+    /// length is how many slots finding a station reads.
+    #[arg(long)]
+    stats: bool,
+
+    /// How to read the input: through a memory mapping, or with several
+    /// large reads in flight through io_uring, which can be faster when
+    /// the input isn't in the page cache.
+    #[arg(long, value_enum, default_value_t = Io::Mmap)]
+    io: Io,
+
+    /// Number of bytes each --io uring read asks for, a multiple of 4096.
+    #[arg(long, default_value_t = 8 << 20)]
+    read_size: usize,
+}
+
+#[cfg_attr(feature = "profiled", inline(never))]
+fn format_stations(stations: impl Iterator<Item = WeatherStation>) -> String {
+    format!(
+        "{{{}}}",
+        stations.map(|station| format!("{station}")).join(", ")
+    )
 }
 
 #[cfg_attr(feature = "profiled", inline(never))]
 fn run() -> BrcResult {
     let args = Args::try_parse()?;
 
-    println!(
-        "{{{}}}",
-        temperature_reading_summaries(&args)?
-            .map(|station| format!("{station}"))
-            .join(", ")
-    );
+    println!("{}", format_stations(temperature_reading_summaries(&args)?));
     Ok(())
 }
 
@@ -329,13 +1145,725 @@ fn main() -> ExitCode {
 
 #[cfg(test)]
 mod test {
-    use crate::parse_temperature;
+    use std::{collections::BTreeMap, time::Duration};
+
+    use brc::{
+        batched_lines::TAIL_SIZE, error::BrcResult, memops::SimdLevel, station_map::MAX_NAME_LEN,
+    };
+    use clap::{Parser, ValueEnum};
+    use itertools::Itertools;
+
+    use crate::{
+        Args, BATCH_WIDTHS, BatchWidthCalibration, DEFAULT_BATCH_WIDTH, DecimalSeparator,
+        Delimiter, Simd, format_stations, temp_file::TempFile, temperature_reading_summaries,
+        test_data::Rng,
+    };
+
+    // The tests below memory map their input, which Miri doesn't support.
+
+    fn parse_args(args: &[&str]) -> Args {
+        Args::try_parse_from(["brc"].iter().chain(args)).unwrap()
+    }
+
+    fn delimiter_char(delimiter: Delimiter) -> char {
+        match delimiter {
+            Delimiter::Semicolon => ';',
+            Delimiter::Tab => '\t',
+            Delimiter::Comma => ',',
+            Delimiter::Pipe => '|',
+        }
+    }
+
+    /// A deliberately naive implementation to check the real one against,
+    /// for the input format args describe.
+    ///
+    /// Readings are rounded half away from zero like `parse_decimal`, and
+    /// averages half up like `TemperatureSummary::avg`, with a small nudge
+    /// to absorb f64 error in values that land exactly on a tie.
+    fn reference_summaries(input: &str, args: &Args) -> String {
+        #[derive(Default)]
+        struct Summary {
+            readings: Vec<i64>,
+            missing: usize,
+        }
+
+        let delim = delimiter_char(args.delimiter);
+        let scale = args.scale;
+        let unit = 10f64.powi(scale as i32);
+        let mut stations: BTreeMap<String, Summary> = BTreeMap::new();
+        let mut lines: Vec<&str> = input.split('\n').collect();
+        if input.is_empty() || input.ends_with('\n') {
+            lines.pop();
+        }
+        let mut lines = lines.into_iter().skip(args.skip_lines);
+        while let Some(line) = lines.next() {
+            if args.skip_blank_lines && line.chars().all(|c| c.is_ascii_whitespace()) {
+                continue;
+            }
+            if let Some(prefix) = &args.comment_prefix
+                && line.starts_with(prefix.as_str())
+            {
+                continue;
+            }
+            let (name, temperature) = if args.quoted_names && line.starts_with('"') {
+                let mut record = line.to_owned();
+                loop {
+                    if let Some((name, rest)) = quoted_field(&record) {
+                        let temperature = rest.strip_prefix(delim).unwrap().to_owned();
+                        break (name, temperature);
+                    }
+                    record = record + "\n" + lines.next().unwrap();
+                }
+            } else {
+                let (name, temperature) = line.rsplit_once(delim).unwrap();
+                (name.to_owned(), temperature.to_owned())
+            };
+            let temperature = temperature.as_str();
+            let summary = stations.entry(name).or_default();
+            if ["", "NaN", "null"].contains(&temperature) {
+                summary.missing += 1;
+            } else {
+                let t: f64 = match args.decimal_separator {
+                    DecimalSeparator::Dot => temperature.parse().unwrap(),
+                    DecimalSeparator::Comma => temperature.replace(',', ".").parse().unwrap(),
+                };
+                let rounded = (t.abs() * unit + 0.5 + 1e-7).floor() as i64;
+                summary
+                    .readings
+                    .push(if t < 0.0 { -rounded } else { rounded });
+            }
+        }
+
+        let fixed = |t: i64| format!("{:.*}", scale as usize, t as f64 / unit);
+        let mut formatted = stations.into_iter().map(|(name, summary)| {
+            let mut result = if summary.readings.is_empty() {
+                format!("{name}=NaN/NaN/NaN")
+            } else {
+                let readings = &summary.readings;
+                let min = *readings.iter().min().unwrap();
+                let max = *readings.iter().max().unwrap();
+                let mean = readings.iter().sum::<i64>() as f64 / readings.len() as f64;
+                let avg = (mean + 0.5 + 1e-7).floor() as i64;
+                format!("{name}={}/{}/{}", fixed(min), fixed(avg), fixed(max))
+            };
+            if summary.missing != 0 {
+                result += &format!(" ({} missing)", summary.missing);
+            }
+            result
+        });
+        format!("{{{}}}", formatted.join(", "))
+    }
+
+    /// Reads the quoted name at the start of record one char at a time,
+    /// returning it unescaped with the rest of the record, or None if it
+    /// isn't closed.
+    fn quoted_field(record: &str) -> Option<(String, &str)> {
+        let mut name = String::new();
+        let mut chars = record.char_indices().skip(1).peekable();
+        while let Some((i, c)) = chars.next() {
+            if c != '"' {
+                name.push(c);
+            } else if chars.next_if(|&(_, c)| c == '"').is_some() {
+                name.push('"');
+            } else {
+                return Some((name, &record[i + 1..]));
+            }
+        }
+        None
+    }
+
+    /// Quotes name like a CSV field.
+    fn quoted(name: &str) -> String {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+
+    fn summaries(input: &str, extra_args: &[&str]) -> String {
+        try_summaries(input, extra_args).unwrap()
+    }
+
+    fn try_summaries(input: &str, extra_args: &[&str]) -> BrcResult<String> {
+        let file = TempFile::new("test", input.as_bytes());
+        let args = Args::try_parse_from(["brc", "--input", file.path()].iter().chain(extra_args));
+        temperature_reading_summaries(&args.unwrap()).map(format_stations)
+    }
+
+    /// Names that have tripped up (or could trip up) the fast paths.
+    fn edge_case_names(delim: char) -> Vec<String> {
+        let mut names: Vec<String> = [
+            "",
+            "A",
+            "Z",
+            "Minus-",
+            "-",
+            "St. John's",
+            "São Paulo",
+            "Ürümqi",
+            "東京",
+            "Île-de-France",
+            "a b",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        // Names containing the delimiter.
+        names.push(format!("Foo{delim}Bar"));
+        names.push(format!("{delim}Lead"));
+        names.push(format!("Trail{delim}"));
+
+        // The longest names that fit, and names around the 32 byte
+        // boundary of `Memops::load32_unchecked`, differing only in their
+        // last byte.
+        for len in [31, 32, 33, 55, 56] {
+            names.push("x".repeat(len));
+            names.push("x".repeat(len - 1) + "y");
+        }
+
+        // Names that a hash of only the length and the bytes at 0, len/4,
+        // len/2 and len-1 can't tell apart.
+        for c in 'a'..='h' {
+            names.push(format!("P{c}xxQxxxR{c}xxxxS"));
+        }
+        names
+    }
+
+    fn random_name(rng: &mut Rng) -> String {
+        let len = 1 + rng.below(56);
+        (0..len)
+            .map(|_| (b'a' + rng.below(26) as u8) as char)
+            .collect()
+    }
+
+    /// A random temperature with up to `scale` fractional digits, or one
+    /// more for `parse_decimal` to round away.
+    fn random_temperature(rng: &mut Rng, scale: u32) -> String {
+        let digits = |rng: &mut Rng, count: u32| -> String {
+            (0..count)
+                .map(|_| (b'0' + rng.below(10) as u8) as char)
+                .collect()
+        };
+        match rng.below(50) {
+            0 => "NaN".to_owned(),
+            1 => "".to_owned(),
+            2 => format!("{}", rng.below(199) as i32 - 99),
+            3 => format!("{}.{}", 100 + rng.below(900), digits(rng, scale + 1)),
+            _ => {
+                let unit = 10usize.pow(scale);
+                let t = rng.below(200 * unit - 1) as i64 - (100 * unit as i64 - 1);
+                let sign = if t < 0 { "-" } else { "" };
+                let (int, frac) = (
+                    t.unsigned_abs() as usize / unit,
+                    t.unsigned_abs() as usize % unit,
+                );
+                match scale {
+                    0 => format!("{sign}{int}"),
+                    _ => format!("{sign}{int}.{frac:0width$}", width = scale as usize),
+                }
+            }
+        }
+    }
+
+    fn random_line(rng: &mut Rng, names: &[String], delim: char, scale: u32) -> String {
+        let name = &names[rng.below(names.len())];
+        format!("{name}{delim}{}\n", random_temperature(rng, scale))
+    }
+
+    fn station_names(rng: &mut Rng, delim: char, random_count: usize) -> Vec<String> {
+        let mut names = edge_case_names(delim);
+        names.extend((0..random_count).map(|_| random_name(rng)));
+        names
+    }
+
+    /// Inputs from `seeds` seeds, each of up to `max_lines` lines of the
+    /// edge case names and `name_count` random ones, delimited by delim,
+    /// with temperatures at `scale`, half of them without a newline at the
+    /// end.
+    fn random_inputs(
+        delim: char,
+        scale: u32,
+        seeds: u64,
+        name_count: usize,
+        max_lines: usize,
+    ) -> impl Iterator<Item = String> {
+        (0..seeds).map(move |seed| {
+            let mut rng = Rng(seed);
+            let names = station_names(&mut rng, delim, name_count);
+            let mut input: String = (0..rng.below(max_lines))
+                .map(|_| random_line(&mut rng, &names, delim, scale))
+                .collect();
+            if rng.below(2) == 0 {
+                input.pop();
+            }
+            input
+        })
+    }
+
+    /// Checks the summaries with args against the reference on each of
+    /// inputs.
+    fn assert_matches_reference(args: &[&str], inputs: impl IntoIterator<Item = String>) {
+        let parsed = parse_args(args);
+        for input in inputs {
+            assert_eq!(
+                summaries(&input, args),
+                reference_summaries(&input, &parsed),
+                "{args:?} input:\n{input}"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_small_inputs_match_reference() {
+        assert_matches_reference(&[], random_inputs(';', 1, 300, 10, 60));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_sizes_around_tail_boundary_match_reference() {
+        // Sizes around the tail's, where the input switches from being
+        // scanned in place to being copied, and around multiples of it.
+        let sizes = [TAIL_SIZE, 2 * TAIL_SIZE, 5 * TAIL_SIZE]
+            .into_iter()
+            .flat_map(|tail| tail - 64..=tail + 64);
+        let inputs = sizes.flat_map(|size| {
+            (0..4).map(move |seed| {
+                let mut rng = Rng(seed * 1000 + size as u64);
+                let names = station_names(&mut rng, ';', 10);
+
+                let mut input = String::new();
+                loop {
+                    // Finish with a line padded to hit the size exactly.
+                    let remaining = size - input.len();
+                    if remaining <= 61 {
+                        input += &format!("{};1.0\n", "p".repeat(remaining - 5));
+                        break;
+                    }
+                    let line = random_line(&mut rng, &names, ';', 1);
+                    if line.len() + 5 <= remaining {
+                        input += &line;
+                    }
+                }
+                assert_eq!(input.len(), size);
+                input
+            })
+        });
+        assert_matches_reference(&[], inputs);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_large_inputs_match_reference() {
+        let inputs = (0..4).map(|seed| {
+            let mut rng = Rng(seed);
+            let names = station_names(&mut rng, ';', 2000);
+            (0..50_000)
+                .map(|_| random_line(&mut rng, &names, ';', 1))
+                .collect()
+        });
+        assert_matches_reference(&[], inputs);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_other_delimiters_match_reference() {
+        for (delimiter, delim) in [("tab", '\t'), ("comma", ','), ("pipe", '|')] {
+            assert_matches_reference(
+                &["--delimiter", delimiter],
+                random_inputs(delim, 1, 50, 10, 100),
+            );
+        }
+    }
+
+    /// Rewrites the readings of input, delimited by delim, with a decimal
+    /// comma.
+    fn with_decimal_comma(input: &str, delim: char) -> String {
+        input
+            .split_inclusive('\n')
+            .map(|line| match line.rsplit_once(delim) {
+                Some((name, reading)) => format!("{name}{delim}{}", reading.replace('.', ",")),
+                None => line.to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_decimal_comma_matches_reference() {
+        for (delimiter, delim) in [("semicolon", ';'), ("tab", '\t'), ("pipe", '|')] {
+            for scale in ["0", "1", "2"] {
+                let inputs = random_inputs(delim, scale.parse().unwrap(), 20, 100, 2000)
+                    .map(|input| with_decimal_comma(&input, delim));
+                assert_matches_reference(
+                    &[
+                        "--decimal-separator",
+                        "comma",
+                        "--delimiter",
+                        delimiter,
+                        "--scale",
+                        scale,
+                    ],
+                    inputs,
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_scales_match_reference() {
+        // Scale 0 parses every reading with `parse_decimal`, the others
+        // take the fast path for readings with one fractional digit.
+        for scale in 0..=3 {
+            let scale_arg = scale.to_string();
+            for extra_args in [
+                &[][..],
+                &["--perfect-hash"],
+                &["--batch-width", "16"],
+                &["--hash-fallback-threshold=-1"],
+            ] {
+                let args = [&["--scale", &scale_arg][..], extra_args].concat();
+                assert_matches_reference(&args, random_inputs(';', scale, 10, 100, 4000));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_simd_levels_match_reference() {
+        for simd in Simd::value_variants() {
+            if !simd.level().is_some_and(SimdLevel::is_supported) {
+                continue;
+            }
+            let simd = simd.to_possible_value().unwrap().get_name().to_owned();
+            assert_matches_reference(&["--simd", &simd], random_inputs(';', 1, 20, 100, 2000));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_hash_seeds_match_reference() {
+        for hash_args in [
+            &["--harden-hash"][..],
+            &["--hash-seed", "0"],
+            &["--hash-seed", "18446744073709551615"],
+        ] {
+            assert_matches_reference(hash_args, random_inputs(';', 1, 20, 100, 2000));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_hash_fallback_matches_reference() {
+        // A negative threshold falls back at the first check, 4096 lookups in,
+        // which rebuilds the map part way through most of these inputs.
+        assert_matches_reference(
+            &["--hash-fallback-threshold=-1"],
+            random_inputs(';', 1, 5, 500, 40_000),
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_perfect_hash_matches_reference() {
+        // Small samples leave stations out of the perfect hash, and
+        // --sample-bytes 0 samples none, so it isn't built.
+        for sample_bytes in ["0", "300", "4000", "4194304"] {
+            assert_matches_reference(
+                &["--perfect-hash", "--sample-bytes", sample_bytes],
+                random_inputs(';', 1, 10, 200, 5000),
+            );
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_prefetch_distances_match_reference() {
+        // The fallback rebuilds the map with batches still queued.
+        for extra_args in [
+            &[][..],
+            &["--hash-fallback-threshold=-1"],
+            &["--perfect-hash"],
+        ] {
+            for distance in ["0", "1", "3", "16"] {
+                let args = [&["--prefetch-distance", distance][..], extra_args].concat();
+                assert_matches_reference(&args, random_inputs(';', 1, 5, 300, 20_000));
+            }
+        }
+        assert!(Args::try_parse_from(["brc", "--prefetch-distance", "17"]).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_batch_widths_match_reference() {
+        // With 100 byte calibrations, auto runs trials on inputs of over
+        // 25600 bytes, which must not leak into the real pass's results.
+        let calibrate = ["--batch-width", "auto", "--calibration-bytes", "100"];
+        for width_args in [
+            &["--batch-width", "1"][..],
+            &["--batch-width", "2"],
+            &["--batch-width", "8"],
+            &["--batch-width", "16"],
+            &calibrate,
+            &[&calibrate[..], &["--perfect-hash"]].concat(),
+        ] {
+            assert_matches_reference(width_args, random_inputs(';', 1, 10, 100, 4000));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, not(target_os = "linux")), ignore)]
+    fn test_uring_matches_reference() {
+        // 4096 byte reads put lines across many read edges.
+        let uring = ["--io", "uring", "--read-size", "4096"];
+        for extra_args in [
+            &[][..],
+            &["--batch-width", "16"],
+            &["--batch-width", "auto", "--calibration-bytes", "100"],
+            &["--perfect-hash", "--sample-bytes", "4000"],
+        ] {
+            assert_matches_reference(
+                &[&uring[..], extra_args].concat(),
+                random_inputs(';', 1, 10, 100, 40_000),
+            );
+        }
+    }
+
+    #[test]
+    #[cfg_attr(any(miri, not(target_os = "linux")), ignore)]
+    fn test_uring_skips_header_lines_like_mmap() {
+        let mut rng = Rng(0);
+        let names = station_names(&mut rng, ';', 100);
+        let lines: String = (0..5000)
+            .map(|_| random_line(&mut rng, &names, ';', 1))
+            .collect();
+        let input = format!("{}\nname;temperature\n{lines}", "#".repeat(10_000));
+        assert_eq!(
+            summaries(
+                &input,
+                &["--io", "uring", "--read-size", "4096", "--skip-lines", "2"]
+            ),
+            summaries(&input, &["--skip-lines", "2"])
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_quoted_names_match_reference() {
+        // Names that only round-trip quoted, each of them quoted on every
+        // line, among the other names, which are quoted on some.
+        let mut special: Vec<String> = [
+            "Foo;Bar",
+            ";",
+            "Say \"hi\"",
+            "\"",
+            "\"\"",
+            "Line\nbreak",
+            "\n",
+            "Two\n\nbreaks",
+            "A\"\n;B",
+            "\"Quoted\";1.0",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        special.push(format!("{}\n{}", "x".repeat(27), "y".repeat(28)));
+        special.push("\"".repeat(MAX_NAME_LEN));
+        let inputs = (0..20).map(|seed| {
+            let mut rng = Rng(seed);
+            let names = [station_names(&mut rng, ';', 100), special.clone()].concat();
+            let mut input: String = (0..rng.below(4000))
+                .map(|_| {
+                    let name = &names[rng.below(names.len())];
+                    let name = if special.contains(name) || rng.below(2) == 0 {
+                        quoted(name)
+                    } else {
+                        name.clone()
+                    };
+                    format!("{name};{}\n", random_temperature(&mut rng, 1))
+                })
+                .collect();
+            if rng.below(2) == 0 {
+                input.pop();
+            }
+            input
+        });
+        let inputs: Vec<String> = inputs.collect();
+        // Calibration trials stop in the middle of some of the names.
+        for extra_args in [
+            &[][..],
+            &["--batch-width", "16"],
+            &["--perfect-hash", "--sample-bytes", "300"],
+            &["--batch-width", "auto", "--calibration-bytes", "100"],
+        ] {
+            let args = [&["--quoted-names"][..], extra_args].concat();
+            assert_matches_reference(&args, inputs.iter().cloned());
+        }
+    }
+
+    /// Random inputs with a few of other_lines at random line boundaries,
+    /// so mostly inside full batches, and one more at the end, where it's
+    /// read from the padded tail.
+    fn inputs_with_other_lines(other_lines: &[String]) -> impl Iterator<Item = String> {
+        random_inputs(';', 1, 30, 100, 3000)
+            .zip(0..)
+            .map(|(input, seed)| {
+                let mut rng = Rng(1000 + seed);
+                let mut lines: Vec<String> =
+                    input.split_inclusive('\n').map(str::to_owned).collect();
+                for _ in 0..1 + rng.below(4) {
+                    let line = &other_lines[rng.below(other_lines.len())];
+                    lines.insert(rng.below(lines.len().max(1)), format!("{line}\n"));
+                }
+                if let Some(last) = lines.last_mut()
+                    && !last.ends_with('\n')
+                {
+                    last.push('\n');
+                }
+                lines.push(other_lines[rng.below(other_lines.len())].clone());
+                if rng.below(2) == 0 {
+                    lines.push("\n".to_owned());
+                }
+                lines.concat()
+            })
+    }
+
+    const LINE_ARGS: [&[&str]; 4] = [
+        &[],
+        &["--batch-width", "16"],
+        &["--perfect-hash", "--sample-bytes", "2000"],
+        &["--skip-lines", "1"],
+    ];
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_comment_lines_match_reference() {
+        // The fast path only checks the first byte of the prefix, so lines
+        // of stations whose names start with it are left for record_line to
+        // tell apart from comments.
+        for prefix in ["#", "#!", "//"] {
+            let first = &prefix[..1];
+            let other_lines = [
+                prefix.to_owned(),
+                format!("{prefix} a;1.0"),
+                format!("{prefix}{prefix};NaN"),
+                format!("{first};2.5"),
+                format!("{first}1;-3.0"),
+                format!("{first}x{prefix};0.7"),
+            ];
+            for extra_args in LINE_ARGS {
+                let args = [&["--comment-prefix", prefix][..], extra_args].concat();
+                assert_matches_reference(&args, inputs_with_other_lines(&other_lines));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_blank_lines_match_reference() {
+        let other_lines = ["", " ", "\t", " \t\r "].map(str::to_owned);
+        for extra_args in LINE_ARGS {
+            let args = [&["--skip-blank-lines"][..], extra_args].concat();
+            assert_matches_reference(&args, inputs_with_other_lines(&other_lines));
+        }
+        assert!(try_summaries("a;1.0\n\nb;2.0\n", &[]).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_only_skipped_lines_match_reference() {
+        let inputs = [
+            "",
+            "\n",
+            "#",
+            "#\n",
+            "#!x;1.0\n#\n##\n",
+            "\n\n\n",
+            " \n\t\n  ",
+            "# a;1.0\n\n  \n#\n",
+        ];
+        for extra_args in LINE_ARGS {
+            let args = [
+                &["--comment-prefix", "#", "--skip-blank-lines"][..],
+                extra_args,
+            ]
+            .concat();
+            assert_matches_reference(&args, inputs.map(str::to_owned));
+            let many = [
+                "#a;1.0\n".repeat(1000),
+                "\n".repeat(1000),
+                " \n#\n".repeat(500),
+            ];
+            assert_matches_reference(&args, many);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_rejects_unclosed_quoted_names() {
+        for input in ["\"Foo;1.0", "\"Foo;1.0\n", "a;1.0\n\"Foo\nBar;1.0\nb;2.0\n"] {
+            let err = try_summaries(input, &["--quoted-names"]).unwrap_err();
+            assert!(err.to_string().contains("Unclosed"), "{err}");
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_rejects_names_too_long_for_the_map() {
+        for extra_args in [&[][..], &["--perfect-hash"], &["--quoted-names"]] {
+            for len in (MAX_NAME_LEN - 2..=MAX_NAME_LEN + 9).chain([100, 300]) {
+                // Quoted names are as long as they are once unquoted.
+                let name = if extra_args.contains(&"--quoted-names") {
+                    quoted(&("N\";".repeat(len) + "N")[..len])
+                } else {
+                    "N".repeat(len)
+                };
+                // Before, in and after the sample, and in the tail.
+                for position in [0, 1, 500, 1999] {
+                    let mut lines: Vec<String> = (0..2000)
+                        .map(|i| format!("s{};{}.5\n", i % 7, i % 40))
+                        .collect();
+                    lines[position] = format!("{name};1.0\n");
+                    let input = lines.concat();
+                    let mut args = vec!["--sample-bytes", "100"];
+                    args.extend(extra_args);
+                    match try_summaries(&input, &args) {
+                        Ok(result) if len <= MAX_NAME_LEN => {
+                            assert_eq!(result, reference_summaries(&input, &parse_args(&args)))
+                        }
+                        Err(err) if len > MAX_NAME_LEN => {
+                            assert!(err.to_string().contains("longer than"), "{err}")
+                        }
+                        result => panic!("len={len} {args:?}: {result:?}"),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_calibration_times_every_width() {
+        let data = "a;1.0\n".repeat(1000);
+        let mut calibration = BatchWidthCalibration::new(data.as_bytes(), data.len(), 20);
+        assert_eq!(calibration.len, 18);
+        let mut trials = vec![];
+        while let Some(width) = calibration.next_trial() {
+            trials.push(width);
+            let fast = width == 8 && trials.len() > BATCH_WIDTHS.len();
+            calibration.record(Duration::from_millis(if fast { 1 } else { 2 }));
+        }
+        assert_eq!(trials, [BATCH_WIDTHS, BATCH_WIDTHS].concat());
+        assert_eq!(calibration.width(), 8);
+
+        // Too short to be worth calibrating.
+        let calibration = BatchWidthCalibration::new(data.as_bytes(), data.len(), 100);
+        assert_eq!(calibration.next_trial(), None);
+        assert_eq!(calibration.width(), DEFAULT_BATCH_WIDTH);
+        assert_eq!(BatchWidthCalibration::fixed(16).width(), 16);
+    }
 
     #[test]
-    fn test_parse_float() {
-        assert_eq!(parse_temperature("  ;-99.9".as_bytes()), -999);
-        assert_eq!(parse_temperature("  ;99.9".as_bytes()), 999);
-        assert_eq!(parse_temperature("  ;-9.9".as_bytes()), -99);
-        assert_eq!(parse_temperature("  ;9.9".as_bytes()), 99);
+    fn test_hash_seed_conflicts_with_harden_hash() {
+        let args = ["brc", "--harden-hash", "--hash-seed", "1"];
+        assert!(Args::try_parse_from(args).is_err());
     }
 }
@@ -1,99 +1,320 @@
-use std::arch::x86_64::{
-    __m256i, _mm256_cmpeq_epi8, _mm256_loadu_si256, _mm256_movemask_epi8, _mm256_set1_epi8,
-};
+//! Unchecked memory operations on short slices, with an implementation
+//! for each SIMD extension the CPU may support. Callers are generic over
+//! `Memops` and get monomorphised once per `SimdLevel`.
+//!
+//! The x86 implementations read past the end of their inputs, which Miri
+//! reports as undefined behavior, so they are left out under Miri and on
+//! other targets, where `Swar` is the default.
+//!
+//! `Bounded` wraps a level for slices that aren't followed by readable
+//! padding. The batched hot path keeps the padding contract on purpose:
+//! `batched_process_lines` copies the end of its input into a padded tail
+//! buffer once, which is cheaper than `Bounded`'s check in every memop.
 
-/// Looks for NEEDLE in the first 64 bytes of haystack.
-#[cfg_attr(feature = "profiled", inline(never))]
-unsafe fn memchr32_unchecked<const NEEDLE: u8>(haystack: &[u8]) -> usize {
-    let ptr = haystack.as_ptr();
-    let haystack_vec = unsafe { _mm256_loadu_si256(ptr as *const __m256i) };
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+mod avx2;
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+mod avx512;
+mod bounded;
+mod scalar;
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+mod sse2;
+mod swar;
 
-    let needle_vec: __m256i = unsafe { _mm256_set1_epi8(NEEDLE as i8) };
-    let cmp = unsafe { _mm256_cmpeq_epi8(haystack_vec, needle_vec) };
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+pub use avx2::Avx2;
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+pub use avx512::Avx512;
+pub use bounded::Bounded;
+pub use scalar::Scalar;
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+pub use sse2::Sse2;
+pub use swar::Swar;
 
-    let mask = unsafe { _mm256_movemask_epi8(cmp) } as u32;
-
-    mask.trailing_zeros() as usize
+/// The instruction sets `Memops` is implemented with, from least to most
+/// capable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+    Scalar,
+    Swar,
+    #[cfg(all(target_arch = "x86_64", not(miri)))]
+    Sse2,
+    #[cfg(all(target_arch = "x86_64", not(miri)))]
+    Avx2,
+    #[cfg(all(target_arch = "x86_64", not(miri)))]
+    Avx512,
 }
 
-/// Looks for NEEDLE in the first 64 bytes of haystack.
-///
-/// This will panic if the character is not present.
-/// This will may read 64 bytes, even if the slice is less than 64 bytes.
-#[cfg_attr(feature = "profiled", inline(never))]
-#[cfg_attr(not(feature = "profiled"), inline(always))]
-pub unsafe fn memchr64_unchecked<const NEEDLE: u8>(haystack: &[u8]) -> usize {
-    unsafe {
-        let r = memchr32_unchecked::<NEEDLE>(haystack);
-        if r < 32 {
-            r
-        } else {
-            32 + memchr32_unchecked::<NEEDLE>(haystack.get_unchecked(32..))
+impl SimdLevel {
+    /// Returns the fastest level the running CPU supports.
+    ///
+    /// This never picks Avx512, which is slower than Avx2 even on Sapphire
+    /// Rapids: a run over 10M lines took 231ms with it and 205ms with Avx2.
+    pub fn detect() -> Self {
+        #[cfg(all(target_arch = "x86_64", not(miri)))]
+        for level in [SimdLevel::Avx2, SimdLevel::Sse2] {
+            if level.is_supported() {
+                return level;
+            }
+        }
+        SimdLevel::Swar
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            SimdLevel::Scalar | SimdLevel::Swar => true,
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            SimdLevel::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            SimdLevel::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            SimdLevel::Avx512 => is_x86_feature_detected!("avx512bw"),
         }
     }
 }
 
-/// Checks that up to the first 32 bytes of a and b are equal.
-///
-/// If the provided slice is <32 bytes, this will read past the end.
-#[cfg_attr(feature = "profiled", inline(never))]
-pub unsafe fn memeq32_unchecked(a: &[u8], b: &[u8]) -> bool {
-    let a_vec = unsafe { _mm256_loadu_si256(a.as_ptr() as *const __m256i) };
-    let b_vec = unsafe { _mm256_loadu_si256(b.as_ptr() as *const __m256i) };
-    let cmp = unsafe { _mm256_cmpeq_epi8(a_vec, b_vec) };
-    let mask = unsafe { _mm256_movemask_epi8(cmp) } as u32;
-    a.len() == b.len() && mask.trailing_ones() >= a.len().min(32) as u32
+pub trait Memops {
+    const LEVEL: SimdLevel;
+
+    /// Whether this actually reads as far past the end of its inputs as
+    /// the safety sections below allow.
+    const READS_PAST_END: bool;
+
+    /// Returns a bitmask of the bytes in block equal to NEEDLE, where
+    /// bit i is set if block[i] == NEEDLE.
+    ///
+    /// # Safety
+    ///
+    /// This never reads past the end of block. It is only unsafe because
+    /// the SIMD implementations require the CPU to support them, like
+    /// every other method here.
+    unsafe fn eq_mask64<const NEEDLE: u8>(block: &[u8; 64]) -> u64;
+
+    /// Returns the first 32 bytes of bytes as little-endian words, with
+    /// the bytes past its end zeroed.
+    ///
+    /// # Safety
+    ///
+    /// This may read 32 bytes, even if the slice is less than 32 bytes.
+    unsafe fn load32_unchecked(bytes: &[u8]) -> [u64; 4];
 }
 
-/// Checks that up to the first 64 bytes of a and b are equal.
-///
-/// If the provided slice is <64 bytes, this may read past the end.
-#[cfg_attr(feature = "profiled", inline(never))]
-pub unsafe fn memeq64_unchecked(a: &[u8], b: &[u8]) -> bool {
-    let res = unsafe { memeq32_unchecked(a, b) };
-    if a.len() <= 32 {
-        res
+/// Returns a mask of the bits below min(64, len).
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+#[inline(always)]
+fn in_bounds_mask(len: usize) -> u64 {
+    if len >= 64 {
+        u64::MAX
     } else {
-        res && unsafe { memeq32_unchecked(a.get_unchecked(32..), b.get_unchecked(32..)) }
+        (1u64 << len) - 1
     }
 }
 
+/// Defines a test running `$check::<M>()` for every `Memops` the CPU
+/// supports.
+#[cfg(test)]
+macro_rules! test_all_levels {
+    ($(#[$attr:meta])* $name:ident, $check:ident) => {
+        #[test]
+        $(#[$attr])*
+        fn $name() {
+            $check::<$crate::memops::Scalar>();
+            $check::<$crate::memops::Bounded<$crate::memops::Scalar>>();
+            $check::<$crate::memops::Swar>();
+            $check::<$crate::memops::Bounded<$crate::memops::Swar>>();
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            {
+                use $crate::memops::{Avx2, Avx512, Bounded, SimdLevel, Sse2};
+
+                if SimdLevel::Sse2.is_supported() {
+                    $check::<Sse2>();
+                    $check::<Bounded<Sse2>>();
+                }
+                if SimdLevel::Avx2.is_supported() {
+                    $check::<Avx2>();
+                    $check::<Bounded<Avx2>>();
+                }
+                if SimdLevel::Avx512.is_supported() {
+                    $check::<Avx512>();
+                    $check::<Bounded<Avx512>>();
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+pub(crate) use test_all_levels;
+
 #[cfg(test)]
 mod test {
-    use crate::memops::{memchr64_unchecked, memeq32_unchecked};
-
-    fn pad64(s: &str) -> String {
-        if s.len() >= 64 {
-            s.to_owned()
-        } else {
-            let mut res = [0u8; 64];
-            (&mut res[..s.len()]).copy_from_slice(s.as_bytes());
-            std::str::from_utf8(&res).unwrap().to_owned()
+    use crate::{
+        guard_page::GuardedPages,
+        memops::{Memops, Scalar, SimdLevel},
+        station_map::{DEFAULT_HASH_SEED, hash64, hash64_with},
+        test_data::Rng,
+    };
+
+    fn check_eq_mask64<M: Memops>() {
+        let mut rng = Rng(1);
+        for _ in 0..1000 {
+            let mut block = [0u8; 64];
+            for c in &mut block {
+                *c = b"ab;\n\x80\xff"[rng.below(6)];
+            }
+            let expected = |needle: u8| {
+                (0..64).fold(0u64, |mask, i| mask | (((block[i] == needle) as u64) << i))
+            };
+            unsafe {
+                assert_eq!(M::eq_mask64::<b';'>(&block), expected(b';'));
+                assert_eq!(M::eq_mask64::<b'\n'>(&block), expected(b'\n'));
+                assert_eq!(M::eq_mask64::<0xff>(&block), expected(0xff));
+            }
         }
     }
 
-    fn safe_memeq32(a: &str, b: &str) -> bool {
-        unsafe { memeq32_unchecked(pad64(a).as_bytes(), pad64(b).as_bytes()) }
+    test_all_levels!(test_eq_mask64, check_eq_mask64);
+
+    /// Checks M against Scalar for needles at every pair of positions in a
+    /// block, and for loads of up to 72 bytes followed by other bytes.
+    fn check_matches_scalar<M: Memops>() {
+        for i in 0..64 {
+            for j in i..64 {
+                let mut block = [b'a'; 64];
+                block[i] = b';';
+                block[j] = b'\n';
+                unsafe {
+                    assert_eq!(
+                        M::eq_mask64::<b';'>(&block),
+                        Scalar::eq_mask64::<b';'>(&block),
+                        "i={i} j={j}"
+                    );
+                    assert_eq!(
+                        M::eq_mask64::<b'\n'>(&block),
+                        Scalar::eq_mask64::<b'\n'>(&block),
+                        "i={i} j={j}"
+                    );
+                }
+            }
+        }
+
+        let buffer: [u8; 160] = std::array::from_fn(|i| 0x80 | i as u8);
+        for len in 0..=72 {
+            let bytes = &buffer[..len];
+            unsafe {
+                assert_eq!(
+                    M::load32_unchecked(bytes),
+                    Scalar::load32_unchecked(bytes),
+                    "len={len}"
+                );
+            }
+        }
+    }
+
+    test_all_levels!(test_matches_scalar, check_matches_scalar);
+
+    /// Checks load32_unchecked on slices followed by only as many readable
+    /// bytes as M may read, and then a PROT_NONE page.
+    fn check_load32_guarded<M: Memops>() {
+        let mut pages = GuardedPages::new(1);
+        let bytes: Vec<u8> = (1..=40).collect();
+        for len in 0..=40 {
+            // Bytes after the slice must be zeroed, even if readable.
+            let slack = if M::READS_PAST_END { 32 } else { 0 };
+            let slice = pages.place(&bytes[..len], slack, 0xff);
+            let mut expected = [0u8; 32];
+            expected[..len.min(32)].copy_from_slice(&bytes[..len.min(32)]);
+            let words = unsafe { M::load32_unchecked(slice) };
+            for i in 0..4 {
+                assert_eq!(
+                    words[i],
+                    u64::from_le_bytes(expected[i * 8..i * 8 + 8].try_into().unwrap()),
+                    "len={len}"
+                );
+            }
+        }
+    }
+
+    test_all_levels!(
+        #[cfg_attr(miri, ignore)]
+        test_load32_guarded,
+        check_load32_guarded
+    );
+
+    fn check_hash64_matches_scalar<M: Memops>() {
+        // Names in keys and lines are followed by at least 64 readable bytes.
+        let mut padded = [0xffu8; 128];
+        for seed in [DEFAULT_HASH_SEED, 0, u64::MAX, 0x0123_4567_89ab_cdef] {
+            for len in 0..=64 {
+                let name: Vec<u8> = (0..len as u8).map(|i| i.wrapping_mul(37)).collect();
+                padded[..len].copy_from_slice(&name);
+                assert_eq!(
+                    unsafe { hash64_with::<M>(&padded[..len], seed) },
+                    hash64(&name, seed),
+                    "len={len} seed={seed:#x}"
+                );
+            }
+        }
+    }
+
+    test_all_levels!(test_hash64_matches_scalar, check_hash64_matches_scalar);
+
+    #[test]
+    fn test_hash64_covers_every_byte() {
+        // Changing any single byte of any name up to 64 bytes long, or its
+        // length, changes the hash.
+        let mut hashes = std::collections::HashSet::new();
+        for len in 0..=64 {
+            let name = vec![b'x'; len];
+            assert!(hashes.insert(hash64(&name, DEFAULT_HASH_SEED)));
+            for i in 0..len {
+                let mut other = name.clone();
+                other[i] = b'y';
+                assert!(
+                    hashes.insert(hash64(&other, DEFAULT_HASH_SEED)),
+                    "len={len} i={i}"
+                );
+            }
+        }
     }
 
     #[test]
-    fn test_memeq32() {
-        assert!(safe_memeq32("abcd", "abcd"));
-        assert!(!safe_memeq32("abcd", "abc"));
-        assert!(!safe_memeq32("aaa", "bbb"));
-        assert!(safe_memeq32(
-            "aaaaaaaabbbbbbbbccccccccdddddddd_AAA",
-            "aaaaaaaabbbbbbbbccccccccdddddddd_BBB"
-        ));
+    fn test_hash64_depends_on_seed() {
+        // Names that collide in a table's low bits under one seed are
+        // spread out under others.
+        let names: Vec<String> = (0..1000).map(|i| format!("USC{i:08}")).collect();
+        let low_bits = |seed: u64| -> Vec<u64> {
+            names
+                .iter()
+                .map(|name| hash64(name.as_bytes(), seed) & 1023)
+                .collect()
+        };
+        let reference = low_bits(DEFAULT_HASH_SEED);
+        for seed in 0..16 {
+            let other = low_bits(seed);
+            let same = reference.iter().zip(&other).filter(|(a, b)| a == b).count();
+            // About one in 1024 would match by chance.
+            assert!(same < 10, "seed={seed} same={same}");
+        }
     }
 
-    fn safe_memchr64<const NEEDLE: u8>(haystack: &str) -> usize {
-        unsafe { memchr64_unchecked::<NEEDLE>(pad64(haystack).as_bytes()) }
+    #[test]
+    fn test_detect_is_supported() {
+        assert!(SimdLevel::detect().is_supported());
+        assert!(SimdLevel::Scalar.is_supported());
     }
 
     #[test]
-    fn test_memchr64() {
-        assert_eq!(safe_memchr64::<b'A'>("aaaAaaa"), 3);
-        assert_eq!(safe_memchr64::<b'A'>("aaaAaaaAaaa"), 3);
+    #[cfg_attr(miri, ignore)]
+    fn test_hash64_reads_within_slice() {
+        let mut pages = GuardedPages::new(1);
+        for len in 0..=64 {
+            let name: Vec<u8> = (0..len as u8).collect();
+            let expected = hash64(&name, DEFAULT_HASH_SEED);
+            assert_eq!(
+                hash64(pages.place(&name, 0, 0), DEFAULT_HASH_SEED),
+                expected
+            );
+        }
     }
 }
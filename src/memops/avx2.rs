@@ -0,0 +1,48 @@
+use std::arch::x86_64::{
+    __m256i, _mm256_and_si256, _mm256_cmpeq_epi8, _mm256_cmpgt_epi8, _mm256_loadu_si256,
+    _mm256_movemask_epi8, _mm256_set1_epi8, _mm256_setr_epi8,
+};
+
+use crate::memops::{Memops, SimdLevel};
+
+pub struct Avx2;
+
+/// Returns a bitmask of the bytes equal to NEEDLE in the 32 bytes at ptr.
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn eq_mask32<const NEEDLE: u8>(ptr: *const u8) -> u32 {
+    let haystack_vec = unsafe { _mm256_loadu_si256(ptr as *const __m256i) };
+    let needle_vec = _mm256_set1_epi8(NEEDLE as i8);
+    _mm256_movemask_epi8(_mm256_cmpeq_epi8(haystack_vec, needle_vec)) as u32
+}
+
+// Functions with #[target_feature] can't be #[inline(always)], but they
+// are still inlined into callers compiled with AVX2 enabled.
+impl Memops for Avx2 {
+    const LEVEL: SimdLevel = SimdLevel::Avx2;
+    const READS_PAST_END: bool = true;
+
+    #[target_feature(enable = "avx2")]
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline)]
+    unsafe fn eq_mask64<const NEEDLE: u8>(block: &[u8; 64]) -> u64 {
+        let ptr = block.as_ptr();
+        let lo = unsafe { eq_mask32::<NEEDLE>(ptr) } as u64;
+        let hi = unsafe { eq_mask32::<NEEDLE>(ptr.add(32)) } as u64;
+        lo | (hi << 32)
+    }
+
+    #[target_feature(enable = "avx2")]
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline)]
+    unsafe fn load32_unchecked(bytes: &[u8]) -> [u64; 4] {
+        let indexes = _mm256_setr_epi8(
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
+            24, 25, 26, 27, 28, 29, 30, 31,
+        );
+        let len_vec = _mm256_set1_epi8(bytes.len().min(32) as i8);
+        let bytes_vec = unsafe { _mm256_loadu_si256(bytes.as_ptr() as *const __m256i) };
+        let in_bounds = _mm256_cmpgt_epi8(len_vec, indexes);
+        unsafe { std::mem::transmute(_mm256_and_si256(bytes_vec, in_bounds)) }
+    }
+}
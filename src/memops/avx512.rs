@@ -0,0 +1,40 @@
+use std::arch::x86_64::{
+    __m512i, _mm512_castsi512_si256, _mm512_cmpeq_epi8_mask, _mm512_loadu_si512,
+    _mm512_maskz_loadu_epi8, _mm512_set1_epi8,
+};
+
+use crate::memops::{Memops, SimdLevel, in_bounds_mask};
+
+/// Compares all 64 bytes at once. Loads are masked to the slice, and
+/// masked out bytes can't fault, so unlike `Avx2` this never reads past
+/// the end of its inputs.
+pub struct Avx512;
+
+/// Loads the first min(64, len) bytes at ptr, zeroing the rest.
+#[target_feature(enable = "avx512bw")]
+#[inline]
+unsafe fn load_masked(ptr: *const u8, mask: u64) -> __m512i {
+    unsafe { _mm512_maskz_loadu_epi8(mask, ptr as *const i8) }
+}
+
+impl Memops for Avx512 {
+    const LEVEL: SimdLevel = SimdLevel::Avx512;
+    const READS_PAST_END: bool = false;
+
+    #[target_feature(enable = "avx512bw")]
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline)]
+    unsafe fn eq_mask64<const NEEDLE: u8>(block: &[u8; 64]) -> u64 {
+        let block_vec = unsafe { _mm512_loadu_si512(block.as_ptr() as *const __m512i) };
+        _mm512_cmpeq_epi8_mask(block_vec, _mm512_set1_epi8(NEEDLE as i8))
+    }
+
+    #[target_feature(enable = "avx512bw")]
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline)]
+    unsafe fn load32_unchecked(bytes: &[u8]) -> [u64; 4] {
+        let mask = in_bounds_mask(bytes.len().min(32));
+        let bytes_vec = unsafe { load_masked(bytes.as_ptr(), mask) };
+        unsafe { std::mem::transmute(_mm512_castsi512_si256(bytes_vec)) }
+    }
+}
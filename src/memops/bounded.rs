@@ -0,0 +1,63 @@
+use std::{marker::PhantomData, mem::MaybeUninit};
+
+use crate::{
+    annotations::likely,
+    memops::{Memops, SimdLevel},
+};
+
+/// The smallest page size of the targets that levels reading past the end
+/// of their inputs run on.
+const PAGE_SIZE: usize = 4096;
+
+/// M's memops for slices with nothing readable after them.
+///
+/// A read past the end of a slice is only done in place if it stays in the
+/// page of the slice's first byte, which is mapped. Otherwise the slice is
+/// copied into a zeroed 64 byte buffer first, which only happens within 64
+/// bytes of the end of a page. So this never faults, for a branch per input
+/// and the occasional copy.
+pub struct Bounded<M>(PhantomData<M>);
+
+/// Returns bytes if n bytes can be read from its start without faulting,
+/// or a copy of it in buffer if not.
+///
+/// The buffer is only initialized when it's used, as zeroing it up front
+/// would cost more than the check.
+#[inline(always)]
+fn readable<'a>(bytes: &'a [u8], n: usize, buffer: &'a mut MaybeUninit<[u8; 64]>) -> &'a [u8] {
+    let in_page = bytes.as_ptr() as usize % PAGE_SIZE <= PAGE_SIZE - n;
+    // The pointer of an empty slice may dangle.
+    if likely(bytes.len() >= n || (!bytes.is_empty() && in_page)) {
+        bytes
+    } else {
+        copy(bytes, buffer)
+    }
+}
+
+/// Copies bytes, which are shorter than 64 bytes, into buffer, zeroing
+/// the rest of it.
+#[cold]
+#[inline(never)]
+fn copy<'a>(bytes: &[u8], buffer: &'a mut MaybeUninit<[u8; 64]>) -> &'a [u8] {
+    let buffer = buffer.write([0; 64]);
+    buffer[..bytes.len()].copy_from_slice(bytes);
+    &buffer[..bytes.len()]
+}
+
+impl<M: Memops> Memops for Bounded<M> {
+    const LEVEL: SimdLevel = M::LEVEL;
+    const READS_PAST_END: bool = false;
+
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    unsafe fn eq_mask64<const NEEDLE: u8>(block: &[u8; 64]) -> u64 {
+        unsafe { M::eq_mask64::<NEEDLE>(block) }
+    }
+
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    unsafe fn load32_unchecked(bytes: &[u8]) -> [u64; 4] {
+        let mut buffer = MaybeUninit::uninit();
+        unsafe { M::load32_unchecked(readable(bytes, 32, &mut buffer)) }
+    }
+}
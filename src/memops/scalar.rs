@@ -0,0 +1,28 @@
+use crate::memops::{Memops, SimdLevel};
+
+/// A fallback for CPUs without any of the SIMD extensions. Unlike the
+/// SIMD implementations, this never reads past the end of its inputs.
+pub struct Scalar;
+
+impl Memops for Scalar {
+    const LEVEL: SimdLevel = SimdLevel::Scalar;
+    const READS_PAST_END: bool = false;
+
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    unsafe fn eq_mask64<const NEEDLE: u8>(block: &[u8; 64]) -> u64 {
+        block
+            .iter()
+            .rev()
+            .fold(0, |mask, &c| (mask << 1) | (c == NEEDLE) as u64)
+    }
+
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    unsafe fn load32_unchecked(bytes: &[u8]) -> [u64; 4] {
+        let mut padded = [0u8; 32];
+        let len = bytes.len().min(32);
+        padded[..len].copy_from_slice(&bytes[..len]);
+        std::array::from_fn(|i| u64::from_le_bytes(padded[i * 8..i * 8 + 8].try_into().unwrap()))
+    }
+}
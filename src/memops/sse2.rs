@@ -0,0 +1,55 @@
+use std::arch::x86_64::{
+    __m128i, _mm_and_si128, _mm_cmpeq_epi8, _mm_cmpgt_epi8, _mm_loadu_si128, _mm_movemask_epi8,
+    _mm_set1_epi8, _mm_setr_epi8,
+};
+
+use crate::memops::{Memops, SimdLevel};
+
+/// SSE2 is part of x86-64, so unlike `Avx2` this needs no runtime check
+/// on the targets the crate currently builds for.
+pub struct Sse2;
+
+/// Returns a bitmask of the bytes equal to NEEDLE in the 16 bytes at ptr.
+#[inline(always)]
+unsafe fn eq_mask16<const NEEDLE: u8>(ptr: *const u8) -> u32 {
+    let haystack_vec = unsafe { _mm_loadu_si128(ptr as *const __m128i) };
+    let needle_vec = unsafe { _mm_set1_epi8(NEEDLE as i8) };
+    (unsafe { _mm_movemask_epi8(_mm_cmpeq_epi8(haystack_vec, needle_vec)) }) as u32
+}
+
+/// Returns a bitmask of the bytes equal to NEEDLE in the 32 bytes at ptr.
+#[inline(always)]
+unsafe fn eq_mask32<const NEEDLE: u8>(ptr: *const u8) -> u32 {
+    unsafe { eq_mask16::<NEEDLE>(ptr) | (eq_mask16::<NEEDLE>(ptr.wrapping_add(16)) << 16) }
+}
+
+impl Memops for Sse2 {
+    const LEVEL: SimdLevel = SimdLevel::Sse2;
+    const READS_PAST_END: bool = true;
+
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    unsafe fn eq_mask64<const NEEDLE: u8>(block: &[u8; 64]) -> u64 {
+        let ptr = block.as_ptr();
+        let lo = unsafe { eq_mask32::<NEEDLE>(ptr) } as u64;
+        let hi = unsafe { eq_mask32::<NEEDLE>(ptr.add(32)) } as u64;
+        lo | (hi << 32)
+    }
+
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    unsafe fn load32_unchecked(bytes: &[u8]) -> [u64; 4] {
+        let len = bytes.len().min(32) as i8;
+        let half = |offset: usize| unsafe {
+            let indexes = _mm_setr_epi8(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+            let len_vec = _mm_set1_epi8(len - offset as i8);
+            let bytes_vec = _mm_loadu_si128(bytes.as_ptr().wrapping_add(offset) as *const __m128i);
+            let words: [u64; 2] =
+                std::mem::transmute(_mm_and_si128(bytes_vec, _mm_cmpgt_epi8(len_vec, indexes)));
+            words
+        };
+        let [w0, w1] = half(0);
+        let [w2, w3] = half(16);
+        [w0, w1, w2, w3]
+    }
+}
@@ -0,0 +1,63 @@
+use crate::memops::{Memops, SimdLevel};
+
+/// Works on u64 words, so it builds for any target. Like `Scalar`, it
+/// never reads past the end of its inputs, so it's also what runs under
+/// Miri.
+pub struct Swar;
+
+const LO: u64 = 0x0101_0101_0101_0101;
+const HI: u64 = 0x8080_8080_8080_8080;
+
+/// Loads 8 bytes as a little-endian word.
+#[inline(always)]
+fn load(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// Loads fewer than 8 bytes as a little-endian word, zero padding the rest.
+#[inline(always)]
+fn load_short(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |word, &byte| (word << 8) | byte as u64)
+}
+
+/// Sets the high bit of each byte of word that is equal to NEEDLE.
+///
+/// Unlike the usual `(x - LO) & !x & HI`, this never flags a byte after
+/// a match, so every flag is exact.
+#[inline(always)]
+fn eq_bytes<const NEEDLE: u8>(word: u64) -> u64 {
+    let x = word ^ (LO * NEEDLE as u64);
+    !(((x & !HI) + !HI) | x | !HI)
+}
+
+impl Memops for Swar {
+    const LEVEL: SimdLevel = SimdLevel::Swar;
+    const READS_PAST_END: bool = false;
+
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    unsafe fn eq_mask64<const NEEDLE: u8>(block: &[u8; 64]) -> u64 {
+        block
+            .chunks_exact(8)
+            .enumerate()
+            .fold(0, |mask, (i, word)| {
+                // Gathers the high bit of each byte into the top byte.
+                let bits =
+                    (eq_bytes::<NEEDLE>(load(word)) >> 7).wrapping_mul(0x0102_0408_1020_4080);
+                mask | ((bits >> 56) << (i * 8))
+            })
+    }
+
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    unsafe fn load32_unchecked(bytes: &[u8]) -> [u64; 4] {
+        std::array::from_fn(|i| match bytes.get(i * 8..) {
+            Some(rest) if rest.len() >= 8 => load(rest),
+            Some(rest) => load_short(rest),
+            None => 0,
+        })
+    }
+}
@@ -0,0 +1,250 @@
+use crate::{
+    annotations::prefetch_read,
+    memops::Memops,
+    station_map::{NameHash, Probe, Slot, StationNameKey, folded_multiply},
+};
+
+/// The average number of names per bucket. Each bucket stores a pilot, so
+/// this trades the size of the pilot table for the time it takes to find
+/// pilots for the last buckets, when few slots are left.
+const NAMES_PER_BUCKET: usize = 4;
+
+/// Buckets whose names don't all fit after this many pilots give up the
+/// build, e.g. because two names share their 64 bit hash.
+const MAX_PILOT: u32 = 1 << 24;
+
+// The fractional digits of e.
+const PILOT_KEY: u64 = 0xb7e1_5162_8aed_2a6a;
+
+/// Maps x uniformly to 0..n with a multiply instead of a division.
+#[inline(always)]
+fn fastrange(x: u64, n: usize) -> usize {
+    ((x as u128 * n as u128) >> 64) as usize
+}
+
+/// A minimal perfect hash from a fixed set of station names to V, in the
+/// style of PTHash.
+///
+/// A name's hash picks a bucket, and the bucket's pilot is mixed into the
+/// hash to pick the name's slot. Pilots are searched for when the map is
+/// built, largest bucket first, so that every name gets a slot of its own
+/// and there are exactly as many slots as names. A lookup is then two
+/// loads and one comparison against the slot's name, which fails for names
+/// that the map wasn't built with.
+///
+/// Slots are laid out as in a `StationMap`, so that comparing the name
+/// usually reads one cache line with the value.
+pub struct PerfectStationMap<V> {
+    pilots: Box<[u32]>,
+    slots: Box<[Slot<V>]>,
+    keys: Box<[StationNameKey]>,
+    name_hash: NameHash,
+}
+
+impl<V> PerfectStationMap<V> {
+    /// Builds a map from the distinct names to default values, or returns
+    /// None if the names are empty or no pilots could be found for them.
+    pub fn new(names: &[&str], name_hash: NameHash) -> Option<Self>
+    where
+        V: Default,
+    {
+        if names.is_empty() {
+            return None;
+        }
+        let hashes: Vec<u64> = names
+            .iter()
+            .map(|name| name_hash.hash(name.as_bytes()))
+            .collect();
+
+        let bucket_count = names.len().div_ceil(NAMES_PER_BUCKET);
+        let mut buckets: Vec<Vec<usize>> = vec![vec![]; bucket_count];
+        for (i, &hash) in hashes.iter().enumerate() {
+            buckets[fastrange(hash, bucket_count)].push(i);
+        }
+        let mut order: Vec<usize> = (0..buckets.len()).collect();
+        order.sort_unstable_by_key(|&b| std::cmp::Reverse(buckets[b].len()));
+
+        let mut pilots = vec![0u32; buckets.len()];
+        let mut slot_of = vec![0usize; names.len()];
+        let mut taken = vec![false; names.len()];
+        let mut slots = Vec::with_capacity(NAMES_PER_BUCKET);
+        for b in order {
+            let bucket = &buckets[b];
+            if bucket.is_empty() {
+                break;
+            }
+            let pilot = (0..MAX_PILOT).find(|&pilot| {
+                slots.clear();
+                bucket.iter().all(|&i| {
+                    let slot = Self::slot(hashes[i], pilot, names.len());
+                    let free = !taken[slot] && !slots.contains(&slot);
+                    slots.push(slot);
+                    free
+                })
+            })?;
+            pilots[b] = pilot;
+            for (&i, &slot) in bucket.iter().zip(&slots) {
+                taken[slot] = true;
+                slot_of[i] = slot;
+            }
+        }
+
+        let mut name_of = vec![0; names.len()];
+        for (i, &slot) in slot_of.iter().enumerate() {
+            name_of[slot] = i;
+        }
+        let keys: Box<[StationNameKey]> = name_of
+            .iter()
+            .map(|&i| StationNameKey::new(names[i]))
+            .collect();
+        Some(PerfectStationMap {
+            pilots: pilots.into(),
+            slots: name_of
+                .iter()
+                .zip(&keys)
+                .map(|(&i, key)| Slot::new(hashes[i], key, V::default()))
+                .collect(),
+            keys,
+            name_hash,
+        })
+    }
+
+    #[inline(always)]
+    fn slot(hash: u64, pilot: u32, len: usize) -> usize {
+        let mixed = folded_multiply(hash ^ PILOT_KEY, PILOT_KEY ^ ((pilot as u64) << 32));
+        fastrange(mixed, len)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// The hash that lookups with precomputed hashes must use.
+    pub fn name_hash(&self) -> &NameHash {
+        &self.name_hash
+    }
+
+    /// The slot of the name with the given hash, if the map has it.
+    #[inline(always)]
+    fn index(&self, hash: u64) -> usize {
+        let bucket = fastrange(hash, self.pilots.len());
+        Self::slot(
+            hash,
+            unsafe { *self.pilots.get_unchecked(bucket) },
+            self.len(),
+        )
+    }
+
+    /// Prefetches the slot a lookup with the given hash reads, which holds
+    /// the value too. This reads the bucket's pilot.
+    #[inline(always)]
+    pub fn prefetch(&self, hash: u64) {
+        prefetch_read(unsafe { self.slots.as_ptr().add(self.index(hash)) });
+    }
+
+    /// Looks name up by its hash from `name_hash`, with M's vectorized
+    /// loads. Returns None for names the map wasn't built with.
+    ///
+    /// # Safety
+    ///
+    /// The name must be followed by enough readable bytes for M's
+    /// `Memops::load32_unchecked` at 32 bytes, as it is in a batched line.
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    pub unsafe fn get_with<M: Memops>(&self, hash: u64, name: &str) -> Option<&V> {
+        let probe = unsafe { Probe::new::<M>(hash, name) };
+        let i = self.index(hash);
+        let slot = unsafe { self.slots.get_unchecked(i) };
+        if unsafe { slot.holds::<M>(self.keys.get_unchecked(i), &probe) } {
+            Some(unsafe { slot.value() })
+        } else {
+            None
+        }
+    }
+
+    /// Resets every value to its default, e.g. after a trial run.
+    pub fn reset_values(&mut self)
+    where
+        V: Default,
+    {
+        for slot in self.slots.iter_mut() {
+            *unsafe { slot.value_mut() } = V::default();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.slots
+            .iter()
+            .zip(self.keys.iter())
+            .map(|(slot, key)| (key.as_str(), unsafe { slot.value() }))
+    }
+}
+
+impl<V> Drop for PerfectStationMap<V> {
+    fn drop(&mut self) {
+        if std::mem::needs_drop::<V>() {
+            for slot in self.slots.iter_mut() {
+                unsafe { slot.drop_value() };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hash::RandomState;
+
+    use crate::{
+        memops::Scalar, perfect_hash::PerfectStationMap, station_map::NameHash, test_data::names,
+    };
+
+    fn perfect_map(names: &[String], name_hash: NameHash) -> PerfectStationMap<usize> {
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        PerfectStationMap::new(&names, name_hash).unwrap()
+    }
+
+    fn get(map: &PerfectStationMap<usize>, name: &str) -> Option<usize> {
+        let hash = map.name_hash().hash(name.as_bytes());
+        // Scalar reads no further than the name.
+        unsafe { map.get_with::<Scalar>(hash, name) }.copied()
+    }
+
+    #[test]
+    fn test_map_gives_every_name_its_own_slot() {
+        for n in [1, 2, 3, 5, 100, 2000] {
+            let names = names(n);
+            for name_hash in [NameHash::Fast(0x5eed), NameHash::Strong(RandomState::new())] {
+                let map = perfect_map(&names, name_hash);
+                assert_eq!(map.len(), n);
+                let mut found: Vec<&str> = map.iter().map(|(name, _)| name).collect();
+                found.sort();
+                let mut expected: Vec<&str> = names.iter().map(String::as_str).collect();
+                expected.sort();
+                assert_eq!(found, expected);
+                for name in &names {
+                    assert_eq!(get(&map, name), Some(0), "{name}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_map_misses_other_names() {
+        let names = names(1000);
+        let map = perfect_map(&names[..500], NameHash::Fast(0));
+        for name in &names[500..] {
+            assert_eq!(get(&map, name), None, "{name}");
+        }
+        assert_eq!(get(&map, ""), None);
+        assert_eq!(get(&map, "Station 1 "), None);
+    }
+
+    #[test]
+    fn test_map_is_not_built_without_names() {
+        assert!(PerfectStationMap::<usize>::new(&[], NameHash::Fast(0)).is_none());
+    }
+}
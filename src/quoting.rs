@@ -0,0 +1,80 @@
+use std::borrow::Cow;
+
+/// Strips CSV-style quotes from a station name, so that `"Foo;Bar"`
+/// becomes `Foo;Bar` and `"Say ""hi"""` becomes `Say "hi"`.
+///
+/// Names that don't start with a quote are returned unchanged. Returns
+/// None if a quoted name isn't closed by its final byte, or has a lone
+/// quote inside it.
+pub fn unquote(name: &[u8]) -> Option<Cow<'_, [u8]>> {
+    let [b'"', body @ .., b'"'] = name else {
+        return if name.first() == Some(&b'"') {
+            None
+        } else {
+            Some(Cow::Borrowed(name))
+        };
+    };
+
+    if !body.contains(&b'"') {
+        return Some(Cow::Borrowed(body));
+    }
+
+    let mut unescaped = Vec::with_capacity(body.len());
+    let mut bytes = body.iter();
+    while let Some(&c) = bytes.next() {
+        if c == b'"' && bytes.next() != Some(&b'"') {
+            return None;
+        }
+        unescaped.push(c);
+    }
+    Some(Cow::Owned(unescaped))
+}
+
+/// Whether line starts a quoted station name that it doesn't close, so
+/// that the name goes on past the line break after it.
+pub fn opens_quote(line: &[u8]) -> bool {
+    // Quotes inside a name come in pairs, so only the closing quote leaves
+    // an odd number of them after the opening one.
+    let [b'"', rest @ ..] = line else {
+        return false;
+    };
+    rest.iter().filter(|&&c| c == b'"').count() % 2 == 0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::quoting::{opens_quote, unquote};
+
+    fn unquote_str(name: &str) -> Option<String> {
+        unquote(name.as_bytes()).map(|s| String::from_utf8(s.into_owned()).unwrap())
+    }
+
+    #[test]
+    fn test_unquote() {
+        assert_eq!(unquote_str("Hamburg").as_deref(), Some("Hamburg"));
+        assert_eq!(unquote_str("Ham\"burg").as_deref(), Some("Ham\"burg"));
+        assert_eq!(unquote_str("\"Foo;Bar\"").as_deref(), Some("Foo;Bar"));
+        assert_eq!(unquote_str("\"\"").as_deref(), Some(""));
+        assert_eq!(
+            unquote_str("\"Say \"\"hi\"\"\"").as_deref(),
+            Some("Say \"hi\"")
+        );
+        assert_eq!(unquote_str("\"\"\"\"").as_deref(), Some("\""));
+        assert_eq!(unquote_str("\""), None);
+        assert_eq!(unquote_str("\"Foo"), None);
+        assert_eq!(unquote_str("\"Foo\"Bar\""), None);
+    }
+
+    #[test]
+    fn test_opens_quote() {
+        assert!(opens_quote(b"\""));
+        assert!(opens_quote(b"\"Foo"));
+        assert!(opens_quote(b"\"Say \"\"hi"));
+        assert!(opens_quote(b"\"Foo;Bar\"\";1.0"));
+        assert!(!opens_quote(b""));
+        assert!(!opens_quote(b"Foo\";1.0"));
+        assert!(!opens_quote(b"\"Foo\";1.0"));
+        assert!(!opens_quote(b"\"Say \"\"hi\"\"\";1.0"));
+        assert!(!opens_quote(b"\"\""));
+    }
+}
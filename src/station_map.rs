@@ -1,15 +1,18 @@
 use std::{
     borrow::Borrow,
-    hash::{BuildHasher, Hasher},
+    hash::{BuildHasher, RandomState},
+    mem::MaybeUninit,
 };
 
+use allocator_api2::boxed::Box;
+
 use crate::{
-    memops::memeq64_unchecked,
+    annotations::{likely, prefetch_read},
+    memops::{Memops, Scalar},
     mmap_allocator::{AllocatorOptions, MmapAllocator},
 };
 
-/// A wrapper type that provides comparisons optimized
-/// for strings that are <64 bytes.
+/// A wrapper type for comparing a str against a `StationNameKey`.
 #[repr(transparent)]
 pub struct StationNameKeyView {
     name: str,
@@ -23,42 +26,87 @@ impl StationNameKeyView {
         // without having to allocate a StationNameKey.
         unsafe { &*(s as *const str as *const StationNameKeyView) }
     }
+}
 
-    #[inline(always)]
-    pub fn hash_u64(&self) -> u64 {
-        hash64(self.name.as_bytes())
-    }
+/// Reads the little-endian word at byte offset of bytes.
+///
+/// # Safety
+///
+/// bytes must be followed by 8 readable bytes from offset.
+#[inline(always)]
+unsafe fn load_word(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le(unsafe { std::ptr::read_unaligned(bytes.as_ptr().add(offset) as *const u64) })
 }
 
 // Taken from FxHash implementation.
 const SEED: u64 = 0xf1357aea2e62a9c5;
 
+/// The seed used unless the hash is hardened, see `NameHash`.
+pub const DEFAULT_HASH_SEED: u64 = SEED;
+
+// The fractional digits of pi, as in foldhash.
+const KEYS: [u64; 4] = [
+    0x243f_6a88_85a3_08d3,
+    0x1319_8a2e_0370_7344,
+    0xa409_3822_299f_31d0,
+    0x082e_fa98_ec4e_6c89,
+];
+
+/// Multiplies a and b into 128 bits and xors the halves together.
+#[inline(always)]
+pub(crate) fn folded_multiply(a: u64, b: u64) -> u64 {
+    let product = (a as u128).wrapping_mul(b as u128);
+    (product as u64) ^ ((product >> 64) as u64)
+}
+
+/// Hashes the whole name, 32 bytes at a time.
+///
+/// Each 32 byte block is mixed like XXH3 mixes long inputs: every word is
+/// xored with a key, and its halves are multiplied together and added to
+/// the other word of its pair. This stays in vector registers until the
+/// products are folded into one word, which is cheaper than extracting all
+/// four words for 64x64 bit multiplies.
+///
+/// The seed is mixed into every word, so that without knowing it, names
+/// can't be picked to collide.
+///
+/// Every level's `Memops::load32_unchecked` zero pads the name in the same
+/// way, so the hash is the same whichever M computes it.
+///
+/// # Safety
+///
+/// The name must be followed by enough readable bytes for M's
+/// `Memops::load32_unchecked` at each multiple of 32 bytes before its end.
 #[cfg_attr(feature = "profiled", inline(never))]
 #[cfg_attr(not(feature = "profiled"), inline(always))]
-pub fn hash64(bytes: &[u8]) -> u64 {
-    unsafe {
-        let len = bytes.len();
-        let p = bytes.as_ptr();
-
-        // Just pick out four bytes more or less at random.
-        // This is somehow about as slow as FxHash.
-        // Might be better to read 8 bytes instead
-        // to be a little more robust.
-        let b0 = *p as u64;
-        let b1 = *p.add(len / 4) as u64;
-        let b2 = *p.add(len / 2) as u64;
-        let b3 = *p.add(len - 1) as u64;
-
-        let x: u64 = (b0 << 56) | (b1 << 48) | (b2 << 40) | (b3 << 32);
-        let mut hash = x ^ (len as u64);
-        hash = hash.wrapping_mul(SEED);
-        hash ^= hash >> 32;
-        hash = hash.wrapping_mul(SEED);
-        hash ^= hash >> 32;
-        hash
+pub unsafe fn hash64_with<M: Memops>(bytes: &[u8], seed: u64) -> u64 {
+    let len = bytes.len();
+    let mut hash = seed ^ len as u64;
+    let mut offset = 0;
+    loop {
+        // Most names fit in a single load.
+        let w = unsafe { M::load32_unchecked(bytes.get_unchecked(offset..)) };
+        let mut acc = 0;
+        // The lanes are independent so that this vectorizes.
+        for i in 0..4 {
+            let x = w[i] ^ KEYS[i] ^ seed;
+            acc ^= (x & 0xffff_ffff)
+                .wrapping_mul(x >> 32)
+                .wrapping_add(w[i ^ 1]);
+        }
+        hash = folded_multiply(acc ^ hash, SEED);
+        offset += 32;
+        if likely(offset >= len) {
+            return hash;
+        }
     }
 }
 
+/// Like `hash64_with`, without reading past the end of bytes.
+pub fn hash64(bytes: &[u8], seed: u64) -> u64 {
+    unsafe { hash64_with::<Scalar>(bytes, seed) }
+}
+
 impl Borrow<StationNameKeyView> for StationNameKey {
     #[inline(always)]
     fn borrow(&self) -> &StationNameKeyView {
@@ -70,20 +118,19 @@ impl PartialEq for StationNameKeyView {
     #[cfg_attr(feature = "profiled", inline(never))]
     #[cfg_attr(not(feature = "profiled"), inline(always))]
     fn eq(&self, other: &Self) -> bool {
-        unsafe { memeq64_unchecked(self.name.as_bytes(), other.name.as_bytes()) }
+        // This is only used off the hot path, whose lookups compare names
+        // with `Slot::holds`.
+        self.name == other.name
     }
 }
 
 impl Eq for StationNameKeyView {}
 
-impl std::hash::Hash for StationNameKeyView {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        state.write_u64(self.hash_u64());
-    }
-}
-
 const INLINE_STRING_SIZE: usize = 56;
 
+/// The longest station name, in bytes, that a `StationMap` holds.
+pub const MAX_NAME_LEN: usize = INLINE_STRING_SIZE;
+
 #[repr(align(64))]
 struct InlineString {
     data: [u8; INLINE_STRING_SIZE],
@@ -93,7 +140,9 @@ struct InlineString {
 impl InlineString {
     fn new(s: &str) -> Self {
         let mut data: [u8; INLINE_STRING_SIZE] = [0; _];
-        (unsafe { data.get_unchecked_mut(..s.as_bytes().len()) }).copy_from_slice(s.as_bytes());
+        // Names are checked against MAX_NAME_LEN before they get here, so
+        // this only panics on a bug.
+        data[..s.len()].copy_from_slice(s.as_bytes());
         InlineString { data, len: s.len() }
     }
 
@@ -103,6 +152,11 @@ impl InlineString {
             std::str::from_utf8_unchecked(s)
         }
     }
+
+    /// The string followed by zeros.
+    fn padded(&self) -> &[u8; INLINE_STRING_SIZE] {
+        &self.data
+    }
 }
 
 pub struct StationNameKey {
@@ -120,6 +174,16 @@ impl StationNameKey {
     pub fn view(&self) -> &StationNameKeyView {
         self.borrow()
     }
+
+    pub fn as_str(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// The name followed by zeros, which can be read past the name's end
+    /// without going outside the key.
+    fn padded(&self) -> &[u8; INLINE_STRING_SIZE] {
+        self.name.padded()
+    }
 }
 
 impl PartialEq for StationNameKey {
@@ -131,60 +195,680 @@ impl PartialEq for StationNameKey {
 
 impl Eq for StationNameKey {}
 
-impl std::hash::Hash for StationNameKey {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        let view: &StationNameKeyView = self.borrow();
-        view.hash(state);
+impl From<StationNameKey> for String {
+    fn from(key: StationNameKey) -> String {
+        key.as_str().to_owned()
     }
 }
 
-impl Into<String> for StationNameKey {
-    fn into(self) -> String {
-        self.name.as_str().to_owned()
+/// The hash of station names in a `StationMap`.
+#[derive(Clone)]
+pub enum NameHash {
+    /// `hash64` with a seed.
+    Fast(u64),
+    /// SipHash with keys from the OS, for when `hash64` collides too often
+    /// on an input.
+    Strong(RandomState),
+}
+
+impl NameHash {
+    #[inline(always)]
+    pub fn hash(&self, bytes: &[u8]) -> u64 {
+        unsafe { self.hash_with::<Scalar>(bytes) }
+    }
+
+    /// Computes `hash` with M's vectorized loads.
+    ///
+    /// # Safety
+    ///
+    /// See `hash64_with`.
+    #[inline(always)]
+    pub unsafe fn hash_with<M: Memops>(&self, bytes: &[u8]) -> u64 {
+        match self {
+            NameHash::Fast(seed) => unsafe { hash64_with::<M>(bytes, *seed) },
+            NameHash::Strong(state) => state.hash_one(bytes),
+        }
     }
 }
 
-#[derive(Default)]
-pub struct NopHasher(u64);
+/// Bytes of the name kept in each `Slot`. Longer names are also compared
+/// with the map's full copy of the name.
+const PREFIX_WORDS: usize = 3;
+const PREFIX_SIZE: usize = PREFIX_WORDS * 8;
+
+/// A station's entry in a `StationMap` or `PerfectStationMap`. With a
+/// `TemperatureSummary` this is one cache line, so that a lookup usually
+/// reads only the slot.
+///
+/// [`PerfectStationMap`]: crate::perfect_hash::PerfectStationMap
+#[repr(C, align(64))]
+pub(crate) struct Slot<V> {
+    /// The upper half of the name's hash. The lower half picks the slot.
+    tag: u32,
+    /// The name's length plus one, or 0 if the slot is empty, so that
+    /// zeroed memory is an empty table.
+    stored_len: u32,
+    /// The start of the name, zero padded as `Memops::load32_unchecked`
+    /// pads it.
+    prefix: [u64; PREFIX_WORDS],
+    value: MaybeUninit<V>,
+}
+
+/// A name being looked up, loaded for comparing against `Slot`s.
+pub(crate) struct Probe<'a> {
+    tag: u32,
+    stored_len: u32,
+    loaded: [u64; 4],
+    bytes: &'a [u8],
+}
 
-impl Hasher for NopHasher {
-    fn finish(&self) -> u64 {
-        self.0
+impl<'a> Probe<'a> {
+    /// # Safety
+    ///
+    /// The name must be followed by enough readable bytes for M's
+    /// `Memops::load32_unchecked`.
+    #[inline(always)]
+    pub(crate) unsafe fn new<M: Memops>(hash: u64, name: &'a str) -> Self {
+        let bytes = name.as_bytes();
+        Probe {
+            tag: (hash >> 32) as u32,
+            stored_len: bytes.len() as u32 + 1,
+            loaded: unsafe { M::load32_unchecked(bytes) },
+            bytes,
+        }
     }
+}
 
-    fn write_u64(&mut self, i: u64) {
-        debug_assert!(self.0 == 0);
-        self.0 = i;
+impl<V> Slot<V> {
+    /// A full slot for key, whose hash is hash.
+    pub(crate) fn new(hash: u64, key: &StationNameKey, value: V) -> Self {
+        let bytes = key.as_str().as_bytes();
+        let loaded = unsafe { Scalar::load32_unchecked(bytes) };
+        Slot {
+            tag: (hash >> 32) as u32,
+            stored_len: bytes.len() as u32 + 1,
+            prefix: std::array::from_fn(|j| loaded[j]),
+            value: MaybeUninit::new(value),
+        }
     }
 
-    fn write(&mut self, _: &[u8]) {
-        panic!("Generic write unsupported for NopHasher");
+    fn is_empty(&self) -> bool {
+        self.stored_len == 0
+    }
+
+    /// Whether this slot, whose full name is key, holds the probed name.
+    ///
+    /// After the tag and length, names are compared by their length class.
+    /// Keys and loaded words are zero padded, so names of up to 8, 16 or
+    /// `PREFIX_SIZE` bytes are all told apart by the whole prefix, which
+    /// is cheaper than branching on which of those they are. Names of up
+    /// to 32 bytes also compare the key's word after the prefix with the
+    /// one the probe has already loaded, and longer ones another 32 byte
+    /// load of each.
+    ///
+    /// # Safety
+    ///
+    /// The probed name must be followed by enough readable bytes for M's
+    /// `Memops::load32_unchecked` at 32 bytes.
+    #[inline(always)]
+    pub(crate) unsafe fn holds<M: Memops>(&self, key: &StationNameKey, probe: &Probe) -> bool {
+        if self.tag != probe.tag
+            || self.stored_len != probe.stored_len
+            || self.prefix != probe.loaded[..PREFIX_WORDS]
+        {
+            return false;
+        }
+        let len = probe.bytes.len();
+        if likely(len <= PREFIX_SIZE) {
+            return true;
+        }
+        let key = key.padded();
+        unsafe {
+            load_word(key, PREFIX_SIZE) == probe.loaded[PREFIX_WORDS]
+                // Names fit in a StationNameKey, so the rest is at most 32
+                // bytes.
+                && (len <= 32
+                    || M::load32_unchecked(&key[32..])
+                        == M::load32_unchecked(probe.bytes.get_unchecked(32..)))
+        }
     }
-}
 
-#[derive(Default)]
-pub struct NopHasherBuilder {}
+    /// The value of a full slot.
+    ///
+    /// # Safety
+    ///
+    /// The slot must not be empty.
+    #[inline(always)]
+    pub(crate) unsafe fn value(&self) -> &V {
+        unsafe { self.value.assume_init_ref() }
+    }
 
-impl BuildHasher for NopHasherBuilder {
-    type Hasher = NopHasher;
-    fn build_hasher(&self) -> Self::Hasher {
-        NopHasher::default()
+    /// The value of a full slot.
+    ///
+    /// # Safety
+    ///
+    /// The slot must not be empty.
+    #[inline(always)]
+    pub(crate) unsafe fn value_mut(&mut self) -> &mut V {
+        unsafe { self.value.assume_init_mut() }
+    }
+
+    /// Drops the value of a full slot, leaving it uninitialized.
+    ///
+    /// # Safety
+    ///
+    /// The slot must not be empty, and its value must not be used again.
+    pub(crate) unsafe fn drop_value(&mut self) {
+        unsafe { self.value.assume_init_drop() }
     }
 }
 
-pub type StationMap<V> = hashbrown::HashMap<StationNameKey, V, NopHasherBuilder, MmapAllocator>;
+/// An open addressing hash table from station names to V, built for the
+/// at most 10k stations of an input.
+///
+/// Slots are probed linearly from the one the name's hash picks, and the
+/// table grows to keep at most a quarter of them full: probing past the
+/// first slot is a hard to predict branch, which at half full costs
+/// inputs with 10k stations about 5%.
+///
+/// A lookup compares the tag, length and prefix in the slot, rather than
+/// going through hashbrown's control bytes to a separate key and value.
+/// Names are also copied to a parallel array of `StationNameKey`s, which
+/// lookups only read for names longer than `PREFIX_SIZE`.
+pub struct StationMap<V> {
+    slots: Box<[Slot<V>], MmapAllocator>,
+    keys: Box<[StationNameKey], MmapAllocator>,
+    mask: usize,
+    len: usize,
+    /// The sum and maximum of the number of slots finding each entry
+    /// reads, which doesn't change once it's inserted.
+    total_probe_length: usize,
+    max_probe_length: usize,
+    name_hash: NameHash,
+    request_hugepage: bool,
+}
 
 pub struct StationMapOptions {
     pub request_hugepage: bool,
+    /// The number of stations the map holds before it grows.
     pub capacity: usize,
+    pub name_hash: NameHash,
 }
 
 pub fn new_station_map<V>(opts: &StationMapOptions) -> StationMap<V> {
-    StationMap::<V>::with_capacity_and_hasher_in(
-        opts.capacity,
-        NopHasherBuilder::default(),
-        MmapAllocator::new(&AllocatorOptions {
-            request_hugepage: opts.request_hugepage,
-        }),
-    )
+    let slots = (opts.capacity.max(1) * 4).next_power_of_two();
+    StationMap::with_slots(slots, opts.name_hash.clone(), opts.request_hugepage)
+}
+
+impl<V> StationMap<V> {
+    fn with_slots(slots: usize, name_hash: NameHash, request_hugepage: bool) -> Self {
+        let alloc = || MmapAllocator::new(&AllocatorOptions { request_hugepage });
+        // Zeroed slots are empty, and zeroed keys are empty names, so the
+        // pages aren't touched until they're used.
+        StationMap {
+            slots: unsafe { Box::new_zeroed_slice_in(slots, alloc()).assume_init() },
+            keys: unsafe { Box::new_zeroed_slice_in(slots, alloc()).assume_init() },
+            mask: slots - 1,
+            len: 0,
+            total_probe_length: 0,
+            max_probe_length: 0,
+            name_hash,
+            request_hugepage,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of stations the map holds before it grows.
+    pub fn capacity(&self) -> usize {
+        self.slots.len() / 4
+    }
+
+    /// Returns the fraction of the map's slots that are full.
+    pub fn load_factor(&self) -> f64 {
+        self.len as f64 / self.slots.len() as f64
+    }
+
+    /// The hash that lookups with precomputed hashes must use.
+    pub fn name_hash(&self) -> &NameHash {
+        &self.name_hash
+    }
+
+    /// Prefetches the first slot a lookup with the given hash reads, which
+    /// holds the value too.
+    #[inline(always)]
+    pub fn prefetch(&self, hash: u64) {
+        prefetch_read(self.slots.as_ptr().wrapping_add(hash as usize & self.mask));
+    }
+
+    /// Finds the slot of the name with the given hash, or the empty slot
+    /// where it would go.
+    ///
+    /// # Safety
+    ///
+    /// See `get_with`.
+    #[inline(always)]
+    unsafe fn find<M: Memops>(&self, hash: u64, name: &str) -> Result<usize, usize> {
+        let probe = unsafe { Probe::new::<M>(hash, name) };
+        let mut i = hash as usize & self.mask;
+        loop {
+            let slot = unsafe { self.slots.get_unchecked(i) };
+            if unsafe { slot.holds::<M>(self.keys.get_unchecked(i), &probe) } {
+                return Ok(i);
+            }
+            if slot.is_empty() {
+                return Err(i);
+            }
+            i = (i + 1) & self.mask;
+        }
+    }
+
+    /// Looks name up by its hash from `name_hash`, with M's vectorized
+    /// loads and comparisons.
+    ///
+    /// # Safety
+    ///
+    /// The name must be followed by enough readable bytes for
+    /// `hash64_with` and M's `Memops::load32_unchecked`, as it is in a
+    /// batched line.
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    pub unsafe fn get_with<M: Memops>(&self, hash: u64, name: &str) -> Option<&V> {
+        match unsafe { self.find::<M>(hash, name) } {
+            Ok(i) => Some(unsafe { self.slots.get_unchecked(i).value() }),
+            Err(_) => None,
+        }
+    }
+
+    /// Returns name's value, inserting a default one if name is new.
+    pub fn get_or_default(&mut self, name: &str) -> &mut V
+    where
+        V: Default,
+    {
+        let hash = self.name_hash.hash(name.as_bytes());
+        // Scalar reads no further than the name.
+        let i = match unsafe { self.find::<Scalar>(hash, name) } {
+            Ok(i) => i,
+            Err(mut i) => {
+                if self.len == self.capacity() {
+                    self.rebuild(self.slots.len() * 2, self.name_hash.clone());
+                    i = unsafe { self.find::<Scalar>(hash, name) }.unwrap_err();
+                }
+                self.insert_at(i, hash, StationNameKey::new(name), V::default());
+                i
+            }
+        };
+        unsafe { self.slots.get_unchecked_mut(i).value.assume_init_mut() }
+    }
+
+    /// Fills the empty slot i.
+    fn insert_at(&mut self, i: usize, hash: u64, key: StationNameKey, value: V) {
+        self.slots[i] = Slot::new(hash, &key, value);
+        self.keys[i] = key;
+        self.len += 1;
+        let probe_length = (i.wrapping_sub(hash as usize) & self.mask) + 1;
+        self.total_probe_length += probe_length;
+        self.max_probe_length = self.max_probe_length.max(probe_length);
+    }
+
+    /// Moves the entry out of slot i, if it's full.
+    fn take(&mut self, i: usize) -> Option<(StationNameKey, V)> {
+        let slot = &mut self.slots[i];
+        if slot.is_empty() {
+            return None;
+        }
+        slot.stored_len = 0;
+        self.len -= 1;
+        let value = unsafe { slot.value.assume_init_read() };
+        Some((StationNameKey::new(self.keys[i].as_str()), value))
+    }
+
+    /// Rehashes the entries with name_hash, e.g. to switch to a stronger
+    /// hash.
+    pub fn set_name_hash(&mut self, name_hash: NameHash) {
+        self.rebuild(self.slots.len(), name_hash);
+    }
+
+    /// Moves the entries into a new table of the given number of slots.
+    #[inline(never)]
+    fn rebuild(&mut self, slots: usize, name_hash: NameHash) {
+        let new = StationMap::with_slots(slots, name_hash, self.request_hugepage);
+        let mut old = std::mem::replace(self, new);
+        for i in 0..old.slots.len() {
+            if let Some((key, value)) = old.take(i) {
+                let hash = self.name_hash.hash(key.as_str().as_bytes());
+                let j = unsafe { self.find::<Scalar>(hash, key.as_str()) }.unwrap_err();
+                self.insert_at(j, hash, key, value);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.slots
+            .iter()
+            .zip(self.keys.iter())
+            .filter(|(slot, _)| !slot.is_empty())
+            .map(|(slot, key)| (key.as_str(), unsafe { slot.value() }))
+    }
+
+    /// How many slots finding each station reads, as counted when they
+    /// were inserted.
+    pub fn probe_lengths(&self) -> ProbeLengths {
+        ProbeLengths {
+            avg: self.total_probe_length as f64 / self.len.max(1) as f64,
+            max: self.max_probe_length,
+        }
+    }
+
+    /// The average probe length a uniformly random hash would give at the
+    /// map's load factor, by Knuth's estimate for linear probing.
+    pub fn uniform_probe_length(&self) -> f64 {
+        0.5 * (1.0 + 1.0 / (1.0 - self.load_factor()))
+    }
+}
+
+impl<V> Drop for StationMap<V> {
+    fn drop(&mut self) {
+        if std::mem::needs_drop::<V>() {
+            for i in 0..self.slots.len() {
+                self.take(i);
+            }
+        }
+    }
+}
+
+pub struct IntoIter<V> {
+    map: StationMap<V>,
+    next: usize,
+}
+
+impl<V> Iterator for IntoIter<V> {
+    type Item = (String, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < self.map.slots.len() {
+            self.next += 1;
+            if let Some((key, value)) = self.map.take(self.next - 1) {
+                return Some((key.into(), value));
+            }
+        }
+        None
+    }
+}
+
+impl<V> IntoIterator for StationMap<V> {
+    type Item = (String, V);
+    type IntoIter = IntoIter<V>;
+
+    fn into_iter(self) -> IntoIter<V> {
+        IntoIter { map: self, next: 0 }
+    }
+}
+
+/// How many slots finding each of a map's stations reads.
+pub struct ProbeLengths {
+    pub avg: f64,
+    pub max: usize,
+}
+
+/// Decides when a map's name hash collides badly enough on the input to
+/// switch to `NameHash::Strong`.
+///
+/// Counting probes in the hot loop's lookups would slow it down, so this
+/// checks `StationMap::probe_lengths`, which the map counts as stations
+/// are inserted, at doubling intervals so that a bad hash is noticed
+/// early, up to every `MAX_CHECK_INTERVAL` lookups.
+pub struct HashFallback {
+    max_extra_probes: f64,
+    lookups: u64,
+    next_check: u64,
+    fell_back_after: Option<u64>,
+}
+
+impl HashFallback {
+    const FIRST_CHECK: u64 = 4096;
+    const MAX_CHECK_INTERVAL: u64 = 1 << 20;
+
+    /// Falls back once finding a station reads at least `max_extra_probes`
+    /// more slots on average than with a uniformly random hash.
+    pub fn new(max_extra_probes: f64) -> Self {
+        HashFallback {
+            max_extra_probes,
+            lookups: 0,
+            next_check: Self::FIRST_CHECK,
+            fell_back_after: None,
+        }
+    }
+
+    /// Counts n more lookups in map, returning true, at most once, if its
+    /// name hash should be replaced.
+    #[inline(always)]
+    pub fn should_fall_back<V>(&mut self, map: &StationMap<V>, n: usize) -> bool {
+        self.lookups += n as u64;
+        if likely(self.lookups < self.next_check) {
+            return false;
+        }
+        self.check(map)
+    }
+
+    #[inline(never)]
+    fn check<V>(&mut self, map: &StationMap<V>) -> bool {
+        self.next_check = self.lookups + self.lookups.min(Self::MAX_CHECK_INTERVAL);
+        if self.fell_back_after.is_some() {
+            return false;
+        }
+        if map.probe_lengths().avg - map.uniform_probe_length() >= self.max_extra_probes {
+            self.fell_back_after = Some(self.lookups);
+            return true;
+        }
+        false
+    }
+
+    pub fn lookups(&self) -> u64 {
+        self.lookups
+    }
+
+    /// The number of lookups after which `should_fall_back` returned true.
+    pub fn fell_back_after(&self) -> Option<u64> {
+        self.fell_back_after
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hash::RandomState;
+
+    use crate::{
+        memops::{Memops, Scalar, test_all_levels},
+        station_map::{
+            HashFallback, MAX_NAME_LEN, NameHash, Probe, Slot, StationMap, StationMapOptions,
+            StationNameKey, new_station_map,
+        },
+        test_data::names,
+    };
+
+    fn options(name_hash: NameHash) -> StationMapOptions {
+        StationMapOptions {
+            request_hugepage: false,
+            capacity: 16,
+            name_hash,
+        }
+    }
+
+    fn station_map(name_hash: NameHash, names: &[String]) -> StationMap<usize> {
+        let mut map = new_station_map(&options(name_hash));
+        for (i, name) in names.iter().enumerate() {
+            *map.get_or_default(name) = i;
+        }
+        map
+    }
+
+    fn assert_finds_keys_by_hash(map: &StationMap<usize>, names: &[String]) {
+        let name_hash = map.name_hash();
+        for (i, name) in names.iter().enumerate() {
+            let value = unsafe { map.get_with::<Scalar>(name_hash.hash(name.as_bytes()), name) };
+            assert_eq!(value, Some(&i), "{name}");
+        }
+        assert_eq!(map.len(), names.len());
+    }
+
+    #[test]
+    fn test_map_finds_keys_by_hash() {
+        let names = names(1000);
+        for name_hash in [NameHash::Fast(0x5eed), NameHash::Strong(RandomState::new())] {
+            let map = station_map(name_hash, &names);
+            assert_finds_keys_by_hash(&map, &names);
+            assert!(map.capacity() >= names.len());
+            assert!(map.load_factor() <= 0.25);
+        }
+    }
+
+    #[test]
+    fn test_map_compares_whole_names() {
+        // These share their length and more than a slot's prefix.
+        let names: Vec<String> = (0..100)
+            .map(|i| format!("{}{i:02}", "x".repeat(30)))
+            .chain(["".to_owned(), "x".to_owned(), "x".repeat(32)])
+            .collect();
+        let map = station_map(NameHash::Fast(0), &names);
+        assert_finds_keys_by_hash(&map, &names);
+        let missing = format!("{}100", "x".repeat(29));
+        let hash = map.name_hash().hash(missing.as_bytes());
+        assert_eq!(unsafe { map.get_with::<Scalar>(hash, &missing) }, None);
+    }
+
+    #[test]
+    fn test_map_compares_names_of_every_length() {
+        // Names of every length that differ from each other in one byte,
+        // at their start, middle and end, so in every word that
+        // `Slot::holds` compares.
+        let mut names = vec![];
+        for len in 1..=56 {
+            for i in [0, len / 2, len - 1] {
+                let mut name = vec![b'a'; len];
+                name[i] = b'b';
+                names.push(String::from_utf8(name).unwrap());
+            }
+        }
+        names.dedup();
+        let map = station_map(NameHash::Fast(0), &names);
+        assert_finds_keys_by_hash(&map, &names);
+    }
+
+    /// Copies name into a buffer, followed by fill, which comparisons must
+    /// ignore.
+    fn padded(name: &[u8], fill: u8) -> [u8; 128] {
+        let mut padded = [fill; 128];
+        padded[..name.len()].copy_from_slice(name);
+        padded
+    }
+
+    fn check_holds_matches_str_eq<M: Memops>() {
+        for len in 0..=MAX_NAME_LEN {
+            let a = vec![b'x'; len];
+            let mut others = vec![a.clone()];
+            if len < MAX_NAME_LEN {
+                others.push(vec![b'x'; len + 1]);
+            }
+            if len > 0 {
+                others.push(vec![b'x'; len - 1]);
+            }
+            for i in 0..len {
+                let mut b = a.clone();
+                b[i] = b'y';
+                others.push(b);
+            }
+            let a = std::str::from_utf8(&a).unwrap();
+            let key = StationNameKey::new(a);
+            // The same hash for every name, so only the names tell them
+            // apart.
+            let slot = Slot::new(0, &key, ());
+            for b in others {
+                for fill in [0, b'x', 0xff] {
+                    let b_padded = padded(&b, fill);
+                    let b = unsafe { std::str::from_utf8_unchecked(&b_padded[..b.len()]) };
+                    let probe = unsafe { Probe::new::<M>(0, b) };
+                    assert_eq!(
+                        unsafe { slot.holds::<M>(&key, &probe) },
+                        a == b,
+                        "{a:?} {b:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    test_all_levels!(test_holds_matches_str_eq, check_holds_matches_str_eq);
+
+    #[test]
+    fn test_map_returns_every_entry() {
+        let names = names(100);
+        let mut map = new_station_map::<String>(&options(NameHash::Fast(0)));
+        for name in &names {
+            map.get_or_default(name).push_str(name);
+        }
+        let mut entries: Vec<(String, String)> = map.into_iter().collect();
+        entries.sort();
+        let mut expected: Vec<(String, String)> = names
+            .iter()
+            .map(|name| (name.clone(), name.clone()))
+            .collect();
+        expected.sort();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_rehashed_map_finds_keys_by_new_hash() {
+        let names = names(1000);
+        let mut map = station_map(NameHash::Fast(0), &names);
+        map.set_name_hash(NameHash::Strong(RandomState::new()));
+        assert!(matches!(map.name_hash(), NameHash::Strong(_)));
+        assert_finds_keys_by_hash(&map, &names);
+    }
+
+    #[test]
+    fn test_probe_lengths_find_every_key() {
+        let names = names(1000);
+        let map = station_map(NameHash::Fast(0), &names);
+        let lengths = map.probe_lengths();
+        // Every key is still where it was inserted, or moved to when the
+        // map grew.
+        let found: Vec<usize> = names
+            .iter()
+            .map(|name| {
+                let hash = map.name_hash().hash(name.as_bytes());
+                let i = unsafe { map.find::<Scalar>(hash, name) }.unwrap();
+                (i.wrapping_sub(hash as usize) & map.mask) + 1
+            })
+            .collect();
+        assert_eq!(lengths.max, *found.iter().max().unwrap());
+        assert_eq!(lengths.avg, found.iter().sum::<usize>() as f64 / 1000.0);
+        assert!(lengths.avg >= 1.0 && lengths.max >= 1);
+        assert!(lengths.avg < map.uniform_probe_length() + 0.5);
+        assert_eq!(station_map(NameHash::Fast(0), &[]).probe_lengths().max, 0);
+    }
+
+    #[test]
+    fn test_hash_fallback_fires_once() {
+        let map = station_map(NameHash::Fast(0), &names(100));
+        let mut never = HashFallback::new(f64::INFINITY);
+        let mut always = HashFallback::new(f64::NEG_INFINITY);
+        let mut checks = vec![];
+        for _ in 0..1 << 14 {
+            assert!(!never.should_fall_back(&map, 16));
+            if always.should_fall_back(&map, 16) {
+                checks.push(always.lookups());
+            }
+        }
+        assert_eq!(checks, [4096]);
+        assert_eq!(always.fell_back_after(), Some(4096));
+        assert_eq!(never.fell_back_after(), None);
+        assert_eq!(never.lookups(), 16 << 14);
+    }
 }
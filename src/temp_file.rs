@@ -0,0 +1,32 @@
+//! Test helper for the tests that read their input from a file, shared by
+//! the library and the binary.
+
+use std::path::PathBuf;
+
+/// A file in the temp directory, removed again when dropped. Its name is
+/// unique to the process and thread, so tests may run in parallel.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(name: &str, contents: &[u8]) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "brc-{name}-{}-{:?}.txt",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,440 @@
+use crate::{
+    annotations::likely,
+    error::{BrcError, BrcResult},
+};
+
+/// The largest number of fractional digits a reading may be scaled by.
+///
+/// This keeps any classic-format reading (at most 99.9) inside an i32
+/// once it has been scaled up.
+pub const MAX_SCALE: u32 = 6;
+
+fn digit_to_i32(d: u8) -> i32 {
+    d.wrapping_sub(b'0') as i32
+}
+
+fn is_digit(d: u8) -> bool {
+    d.wrapping_sub(b'0') < 10
+}
+
+/// Returns true for characters that can't be used as separators.
+fn is_reserved_char(d: u8) -> bool {
+    is_digit(d) | (d == b'-') | (d == b'+') | (d == b'\n')
+}
+
+/// Loads the last 8 bytes of line as a little-endian word, zero padding
+/// lines shorter than that at the low end.
+#[inline(always)]
+fn load_last8(line: &[u8]) -> u64 {
+    if likely(line.len() >= 8) {
+        u64::from_le_bytes(unsafe { *(line.as_ptr().add(line.len() - 8) as *const [u8; 8]) })
+    } else {
+        let mut word = [0u8; 8];
+        word[8 - line.len()..].copy_from_slice(line);
+        u64::from_le_bytes(word)
+    }
+}
+
+/// Parses a float of the form [-][d]d.d from the end of a line with a
+/// single 8 byte load, where . may be any byte.
+///
+/// The number must be preceded by a byte that isn't a digit, such as
+/// the delimiter.
+#[cfg_attr(feature = "profiled", inline(never))]
+#[cfg_attr(not(feature = "profiled"), inline(always))]
+pub fn parse_temperature_swar(line: &[u8]) -> i32 {
+    // Loading from the end puts the fractional digit in byte 7 and the
+    // decimal point in byte 6, e.g. for ;-9.9 and ;99.9:
+    //
+    //   byte:  0  1  2  3  4  5  6  7
+    //          .  .  .  ;  -  9  .  9
+    //          .  .  .  ;  9  9  .  9
+    //
+    // So rather than the decimal point, what we look for is the '-' or
+    // delimiter below the integer digits, which is the highest byte
+    // under the decimal point that isn't a digit.
+    let word = load_last8(line);
+
+    // Each byte of x is below 10 iff it was a digit. Adding 0x76 to the
+    // low 7 bits sets the high bit for 10 and above, without carrying
+    // into the next byte.
+    let x = word ^ 0x3030_3030_3030_3030;
+    let non_digits =
+        (((x & 0x7f7f_7f7f_7f7f_7f7f) + 0x7676_7676_7676_7676) | x) & 0x0000_8080_8080_8080;
+    // The high bit of the byte before the digits, i.e. 31 or 39.
+    let sign_bit = 63 - non_digits.leading_zeros();
+    let is_negative = ((word >> (sign_bit - 7)) as u8 == b'-') as i32;
+
+    // Move the digits to bytes 1, 2 and 4, dropping the decimal point
+    // and anything at or below the sign, so that multiplying sums
+    // 100 * d1 + 10 * d2 + d4 into bits 32 and up. The other products
+    // land on multiples of 1 << 42, or can't carry into bit 32.
+    let digits = (word >> 24) & 0x0f_000f_0f00 & (u64::MAX << (sign_bit - 23));
+    let abs = ((digits.wrapping_mul(0x640a_0001) >> 32) & 0x3ff) as i32;
+
+    (abs ^ -is_negative) + is_negative
+}
+
+/// Returns true if `field` is of the form [-][d]d.d, which is the
+/// only shape `parse_temperature_swar` understands. The . is DECIMAL.
+#[inline(always)]
+fn is_classic_format<const DECIMAL: u8>(field: &[u8]) -> bool {
+    match *field {
+        [b'-', a, dot, b] | [a, dot, b] => (dot == DECIMAL) & is_digit(a) & is_digit(b),
+        [b'-', a, b, dot, c] | [a, b, dot, c] => {
+            (dot == DECIMAL) & is_digit(a) & is_digit(b) & is_digit(c)
+        }
+        _ => false,
+    }
+}
+
+/// Parses a decimal number of the form [+-]d*[.d*] into a fixed-point
+/// integer with `scale` fractional digits, where . is `decimal_separator`.
+///
+/// Extra fractional digits are rounded half away from zero. Returns None
+/// if the field isn't a number or doesn't fit in an i32 once scaled.
+pub fn parse_decimal(field: &[u8], scale: u32, decimal_separator: u8) -> Option<i32> {
+    let (is_negative, digits) = match field {
+        [b'-', rest @ ..] => (true, rest),
+        [b'+', rest @ ..] => (false, rest),
+        _ => (false, field),
+    };
+
+    let (int_part, frac_part) = match digits.iter().position(|&c| c == decimal_separator) {
+        Some(dot) => (&digits[..dot], &digits[dot + 1..]),
+        None => (digits, &[][..]),
+    };
+    if int_part.is_empty() && frac_part.is_empty() {
+        return None;
+    }
+    if !int_part.iter().chain(frac_part).all(|&c| is_digit(c)) {
+        return None;
+    }
+
+    let mut result: i64 = 0;
+    for &c in int_part {
+        result = result
+            .checked_mul(10)?
+            .checked_add(digit_to_i32(c) as i64)?;
+    }
+    for i in 0..scale as usize {
+        let digit = frac_part.get(i).map_or(0, |&c| digit_to_i32(c));
+        result = result.checked_mul(10)?.checked_add(digit as i64)?;
+    }
+    if frac_part.get(scale as usize).is_some_and(|&c| c >= b'5') {
+        result += 1;
+    }
+
+    if is_negative {
+        result = -result;
+    }
+    i32::try_from(result).ok()
+}
+
+/// The result of parsing the temperature field of a line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reading {
+    /// A temperature, as a fixed-point integer.
+    Value(i32),
+    /// One of the configured missing-value tokens.
+    Missing,
+    /// Neither a number nor a missing-value token.
+    Malformed,
+}
+
+pub struct TemperatureParserOptions<'a> {
+    /// Number of fractional digits to keep for each reading.
+    pub scale: u32,
+    /// Fields that mark a reading as missing, e.g. "NaN" or "".
+    pub missing_tokens: &'a [String],
+}
+
+/// Parses the temperature field of a line into a fixed-point integer.
+///
+/// Fields are separated by DELIM and DECIMAL separates the integer
+/// and fractional digits. These are const parameters so that the
+/// default ;/. format compiles to the same code as a hard-coded one.
+pub struct TemperatureParser<const DELIM: u8, const DECIMAL: u8> {
+    scale: u32,
+    classic_multiplier: i32,
+    use_fast_path: bool,
+    missing_tokens: Vec<Box<[u8]>>,
+}
+
+impl<const DELIM: u8, const DECIMAL: u8> TemperatureParser<DELIM, DECIMAL> {
+    pub fn new(opts: &TemperatureParserOptions) -> BrcResult<Self> {
+        if DELIM == DECIMAL || [DELIM, DECIMAL].iter().any(|&c| is_reserved_char(c)) {
+            return Err(BrcError::new(format!(
+                "Cannot use {:?} as the delimiter with {:?} as the decimal separator",
+                DELIM as char, DECIMAL as char
+            ))
+            .into());
+        }
+
+        let scale = opts.scale;
+        if scale > MAX_SCALE {
+            return Err(
+                BrcError::new(format!("Scale must be at most {MAX_SCALE}, got {scale}")).into(),
+            );
+        }
+
+        let missing_tokens: Vec<Box<[u8]>> = opts
+            .missing_tokens
+            .iter()
+            .map(|token| token.as_bytes().into())
+            .collect();
+
+        // A sentinel like "-99.9" would be read as a value by the fast path.
+        let use_fast_path = scale != 0
+            && !missing_tokens
+                .iter()
+                .any(|t| is_classic_format::<DECIMAL>(t));
+
+        Ok(Self {
+            scale,
+            classic_multiplier: 10i32.pow(scale.saturating_sub(1)),
+            use_fast_path,
+            missing_tokens,
+        })
+    }
+
+    /// The number of fractional digits readings are stored with.
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Parses the temperature following the delimiter at `delim_idx`.
+    ///
+    /// Readings of the form [-][d]d.d go through the branchless
+    /// `parse_temperature_swar`, and everything else through `parse_slow`.
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    pub fn parse(&self, line: &[u8], delim_idx: usize) -> Reading {
+        let field = unsafe { line.get_unchecked(delim_idx + 1..) };
+        if likely(self.use_fast_path & is_classic_format::<DECIMAL>(field)) {
+            Reading::Value(parse_temperature_swar(line) * self.classic_multiplier)
+        } else {
+            self.parse_slow(field)
+        }
+    }
+
+    #[inline(never)]
+    fn parse_slow(&self, field: &[u8]) -> Reading {
+        if self.missing_tokens.iter().any(|token| **token == *field) {
+            Reading::Missing
+        } else if let Some(temp) = parse_decimal(field, self.scale, DECIMAL) {
+            Reading::Value(temp)
+        } else {
+            Reading::Malformed
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cmov::Cmov;
+
+    use crate::temperature_parser::{
+        Reading, TemperatureParser, TemperatureParserOptions, digit_to_i32, parse_decimal,
+        parse_temperature_swar,
+    };
+
+    /// Parses a float of the form ;[-][d]d.d from the end of a string,
+    /// where ; is DELIM, the way the binary did before
+    /// `parse_temperature_swar`, to check that against.
+    ///
+    /// The string must be at least 5 bytes long.
+    fn parse_temperature<const DELIM: u8>(line: &[u8]) -> i32 {
+        let p = line.as_ptr().wrapping_add(line.len() - 1);
+
+        // options: ;-99.9 or ;-9.9 or ;99.9 or ;9.9
+        //               ^        ^        ^       ^
+        let c0 = unsafe { *p };
+
+        // options: ;-99.9 or ;-9.9 or ;99.9 or ;9.9
+        //             ^        ^        ^       ^
+        let c1 = unsafe { *p.sub(2) };
+
+        // options: ;-99.9 or ;-9.9 or ;99.9 or ;9.9
+        //            ^        ^        ^       ^
+        let c2 = unsafe { *p.sub(3) };
+
+        // options: ;-99.9 or ;-9.9 or ;99.9 or ;9.9
+        //           ^        ^        ^       ^
+        let c3 = unsafe { *p.sub(4) };
+
+        // In ;9.9, c3 is the last byte of the station name, which could be a '-'.
+        let is_two_digits = (c2 == DELIM) | (c2 == b'-');
+        let is_negative = ((c3 == b'-') & (c2 != DELIM)) | (c2 == b'-');
+
+        let mut hundreds = digit_to_i32(c2);
+        hundreds.cmovnz(&0, is_two_digits as u8);
+
+        let mut result = 10 * (10 * hundreds + digit_to_i32(c1)) + digit_to_i32(c0);
+        let negative_result = -result;
+        result.cmovnz(&negative_result, is_negative as u8);
+
+        result
+    }
+
+    fn parser(scale: u32, missing_tokens: &[&str]) -> TemperatureParser<b';', b'.'> {
+        let missing_tokens: Vec<String> = missing_tokens.iter().map(|t| t.to_string()).collect();
+        TemperatureParser::new(&TemperatureParserOptions {
+            scale,
+            missing_tokens: &missing_tokens,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_float() {
+        assert_eq!(parse_temperature::<b';'>("  ;-99.9".as_bytes()), -999);
+        assert_eq!(parse_temperature::<b';'>("  ;99.9".as_bytes()), 999);
+        assert_eq!(parse_temperature::<b';'>("  ;-9.9".as_bytes()), -99);
+        assert_eq!(parse_temperature::<b';'>("  ;9.9".as_bytes()), 99);
+        assert_eq!(parse_temperature::<b';'>("a-;9.9".as_bytes()), 99);
+        assert_eq!(parse_temperature::<b';'>("a-;-9.9".as_bytes()), -99);
+        assert_eq!(parse_temperature::<b';'>("a-;99.9".as_bytes()), 999);
+    }
+
+    #[test]
+    fn test_parse_swar_matches_parse_temperature() {
+        // Station names that put the start of the line in different
+        // bytes of the load, or end in a '-'.
+        let names = ["", "a", "a-", "-", "ab;cd", "Abéché", "Some Longer Name"];
+        for t in -999i32..=999 {
+            let (int, frac) = (t.abs() / 10, t.abs() % 10);
+            let signs: &[&str] = match t {
+                ..0 => &["-"],
+                0 => &["", "-"],
+                _ => &[""],
+            };
+            let mut ints = vec![format!("{int}")];
+            if int < 10 {
+                ints.push(format!("0{int}"));
+            }
+
+            for sign in signs {
+                for int in &ints {
+                    for name in names {
+                        let line = format!("{name};{sign}{int}.{frac}");
+                        let line = line.as_bytes();
+                        assert_eq!(parse_temperature_swar(line), t, "{line:?}");
+                        if line.len() >= 5 {
+                            assert_eq!(parse_temperature::<b';'>(line), t, "{line:?}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_decimal() {
+        assert_eq!(parse_decimal(b"-123.45", 2, b'.'), Some(-12345));
+        assert_eq!(parse_decimal(b"7", 1, b'.'), Some(70));
+        assert_eq!(parse_decimal(b"101.0", 1, b'.'), Some(1010));
+        assert_eq!(parse_decimal(b"+3.", 0, b'.'), Some(3));
+        assert_eq!(parse_decimal(b".5", 2, b'.'), Some(50));
+        assert_eq!(parse_decimal(b"1.25", 1, b'.'), Some(13));
+        assert_eq!(parse_decimal(b"-1.25", 1, b'.'), Some(-13));
+        assert_eq!(parse_decimal(b"-0.04", 1, b'.'), Some(0));
+        assert_eq!(parse_decimal(b"", 1, b'.'), None);
+        assert_eq!(parse_decimal(b"-", 1, b'.'), None);
+        assert_eq!(parse_decimal(b".", 1, b'.'), None);
+        assert_eq!(parse_decimal(b"1.2.3", 1, b'.'), None);
+        assert_eq!(parse_decimal(b"NaN", 1, b'.'), None);
+        assert_eq!(parse_decimal(b"99999999999", 1, b'.'), None);
+    }
+
+    #[test]
+    fn test_parser_matches_fast_path() {
+        let parser = parser(1, &[]);
+        for t in -999i32..=999 {
+            let line = format!(
+                "Station;{}{}.{}",
+                if t < 0 { "-" } else { "" },
+                t.abs() / 10,
+                t.abs() % 10
+            );
+            assert_eq!(
+                parser.parse(line.as_bytes(), 7),
+                Reading::Value(t),
+                "{line}"
+            );
+            assert_eq!(
+                parse_decimal(&line.as_bytes()[8..], 1, b'.'),
+                Some(t),
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parser_scale() {
+        let p = parser(2, &[]);
+        assert_eq!(p.parse(b"a;-12.3", 1), Reading::Value(-1230));
+        assert_eq!(p.parse(b"a;-123.45", 1), Reading::Value(-12345));
+        assert_eq!(p.parse(b"a;7", 1), Reading::Value(700));
+        assert_eq!(p.parse(b";1.5", 0), Reading::Value(150));
+
+        let p = parser(0, &[]);
+        assert_eq!(p.parse(b"a;-12.5", 1), Reading::Value(-13));
+        assert_eq!(p.parse(b"a;7", 1), Reading::Value(7));
+
+        assert!(
+            TemperatureParser::<b';', b'.'>::new(&TemperatureParserOptions {
+                scale: 7,
+                missing_tokens: &[],
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parser_missing_tokens() {
+        let p = parser(1, &["", "NaN", "null"]);
+        assert_eq!(p.parse(b"Hamburg;NaN", 7), Reading::Missing);
+        assert_eq!(p.parse(b"Hamburg;", 7), Reading::Missing);
+        assert_eq!(p.parse(b"Hamburg;null", 7), Reading::Missing);
+        assert_eq!(p.parse(b"Hamburg;nan", 7), Reading::Malformed);
+        assert_eq!(p.parse(b"Hamburg;12.3", 7), Reading::Value(123));
+
+        let p = parser(1, &[]);
+        assert_eq!(p.parse(b"Hamburg;NaN", 7), Reading::Malformed);
+        assert_eq!(p.parse(b"Hamburg;", 7), Reading::Malformed);
+
+        let p = parser(1, &["-99.9"]);
+        assert_eq!(p.parse(b"Hamburg;-99.9", 7), Reading::Missing);
+        assert_eq!(p.parse(b"Hamburg;-99.8", 7), Reading::Value(-998));
+    }
+
+    #[test]
+    fn test_parser_decimal_comma() {
+        let p = TemperatureParser::<b'\t', b','>::new(&TemperatureParserOptions {
+            scale: 1,
+            missing_tokens: &[],
+        })
+        .unwrap();
+        assert_eq!(p.parse(b"Berlin\t-3,4", 6), Reading::Value(-34));
+        assert_eq!(p.parse(b"Berlin\t13,4", 6), Reading::Value(134));
+        assert_eq!(p.parse(b"Berlin\t7", 6), Reading::Value(70));
+        assert_eq!(p.parse(b"Berlin\t-123,45", 6), Reading::Value(-1235));
+        assert_eq!(p.parse(b"Berlin\t-3.4", 6), Reading::Malformed);
+
+        assert_eq!(parse_decimal(b"-3,45", 2, b','), Some(-345));
+        assert_eq!(parse_temperature::<b'|'>(b"a|9.9"), 99);
+        assert_eq!(parse_temperature::<b'|'>(b"a|-9.9"), -99);
+    }
+
+    #[test]
+    fn test_parser_rejects_ambiguous_format() {
+        let opts = TemperatureParserOptions {
+            scale: 1,
+            missing_tokens: &[],
+        };
+        assert!(TemperatureParser::<b',', b','>::new(&opts).is_err());
+        assert!(TemperatureParser::<b'-', b'.'>::new(&opts).is_err());
+        assert!(TemperatureParser::<b',', b'.'>::new(&opts).is_ok());
+    }
+}
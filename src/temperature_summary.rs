@@ -1,11 +1,14 @@
 use std::cell::Cell;
 
+use crate::annotations::likely;
+
 #[repr(align(32))]
 pub struct TemperatureSummary {
     min: Cell<i32>,
     max: Cell<i32>,
     total: Cell<i64>,
     count: Cell<i32>,
+    missing: Cell<i32>,
 }
 
 impl TemperatureSummary {
@@ -17,9 +20,21 @@ impl TemperatureSummary {
         self.max.get()
     }
 
-    pub fn avg(&self) -> f64 {
-        let rounded_total = self.total.get() + (self.count.get() / 2) as i64;
-        rounded_total.div_euclid(self.count.get() as i64) as f64 / 10.0
+    /// The number of readings, not counting missing ones.
+    pub fn count(&self) -> i32 {
+        self.count.get()
+    }
+
+    /// The number of lines whose reading was a missing-value token.
+    pub fn missing(&self) -> i32 {
+        self.missing.get()
+    }
+
+    /// The average reading, rounded to the same fixed-point scale as the readings.
+    ///
+    /// Must only be called if there's at least one reading.
+    pub fn avg(&self) -> i64 {
+        rounded_avg(self.total.get(), self.count.get())
     }
 
     #[cfg_attr(feature = "profiled", inline(never))]
@@ -30,6 +45,10 @@ impl TemperatureSummary {
         self.total.set(self.total.get() + temp as i64);
         self.count.set(self.count.get() + 1);
     }
+
+    pub fn add_missing(&self) {
+        self.missing.set(self.missing.get() + 1);
+    }
 }
 
 impl Default for TemperatureSummary {
@@ -39,6 +58,7 @@ impl Default for TemperatureSummary {
             max: Cell::new(i32::MIN),
             total: Cell::new(0),
             count: Cell::new(0),
+            missing: Cell::new(0),
         }
     }
 }
@@ -50,6 +70,7 @@ impl TemperatureSummary {
             max: Cell::new(temp),
             total: Cell::new(temp as i64),
             count: Cell::new(1),
+            missing: Cell::new(0),
         }
     }
 
@@ -58,5 +79,303 @@ impl TemperatureSummary {
         self.min.set(self.min.get().min(t.min.get()));
         self.count.set(self.count.get() + t.count.get());
         self.total.set(self.total.get() + t.total.get());
+        self.missing.set(self.missing.get() + t.missing.get());
+    }
+}
+
+fn rounded_avg(total: i64, count: i32) -> i64 {
+    let rounded_total = total + (count / 2) as i64;
+    rounded_total.div_euclid(count as i64)
+}
+
+/// Bits of `PackedTemperatureSummary::total_count` that hold the count.
+/// Readings fit in an i16, so the total of this many fits in the other 40.
+const COUNT_BITS: u32 = 24;
+const COUNT_MASK: u64 = (1 << COUNT_BITS) - 1;
+
+/// The `total_count` of a promoted summary, a count no other summary
+/// reaches.
+const PROMOTED: u64 = COUNT_MASK;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct PackedHead {
+    min: i16,
+    max: i16,
+    missing: i32,
+}
+
+#[derive(Clone, Copy)]
+union HeadOrWide {
+    head: PackedHead,
+    wide: *mut TemperatureSummary,
+}
+
+/// A 16 byte `TemperatureSummary`, for readings that fit in an i16, with
+/// the same methods.
+///
+/// The total and the count share a u64, the count in the low `COUNT_BITS`,
+/// so a reading is recorded with one add. A reading outside i16, or one
+/// that would fill the count, promotes the summary: its readings move to a
+/// boxed `TemperatureSummary`, which it points to from then on.
+#[repr(C, align(16))]
+pub struct PackedTemperatureSummary {
+    head: Cell<HeadOrWide>,
+    total_count: Cell<u64>,
+}
+
+impl PackedTemperatureSummary {
+    fn head(&self) -> PackedHead {
+        debug_assert!(self.wide().is_none());
+        unsafe { self.head.get().head }
+    }
+
+    fn wide(&self) -> Option<&TemperatureSummary> {
+        (self.total_count.get() == PROMOTED).then(|| unsafe { &*self.head.get().wide })
+    }
+
+    fn total(&self) -> i64 {
+        self.total_count.get() as i64 >> COUNT_BITS
+    }
+
+    pub fn min(&self) -> i32 {
+        match self.wide() {
+            Some(wide) => wide.min(),
+            None if self.count() == 0 => i32::MAX,
+            None => self.head().min as i32,
+        }
+    }
+
+    pub fn max(&self) -> i32 {
+        match self.wide() {
+            Some(wide) => wide.max(),
+            None if self.count() == 0 => i32::MIN,
+            None => self.head().max as i32,
+        }
+    }
+
+    /// The number of readings, not counting missing ones.
+    pub fn count(&self) -> i32 {
+        match self.wide() {
+            Some(wide) => wide.count(),
+            None => (self.total_count.get() & COUNT_MASK) as i32,
+        }
+    }
+
+    /// The number of lines whose reading was a missing-value token.
+    pub fn missing(&self) -> i32 {
+        match self.wide() {
+            Some(wide) => wide.missing(),
+            None => self.head().missing,
+        }
+    }
+
+    /// See `TemperatureSummary::avg`.
+    pub fn avg(&self) -> i64 {
+        match self.wide() {
+            Some(wide) => wide.avg(),
+            None => rounded_avg(self.total(), self.count()),
+        }
+    }
+
+    #[cfg_attr(feature = "profiled", inline(never))]
+    #[cfg_attr(not(feature = "profiled"), inline(always))]
+    pub fn add_reading(&self, temp: i32) {
+        let total_count = self.total_count.get();
+        // Also false for promoted summaries.
+        if likely((total_count & COUNT_MASK) < COUNT_MASK - 1 && temp as i16 as i32 == temp) {
+            let head = unsafe { self.head.get().head };
+            self.head.set(HeadOrWide {
+                head: PackedHead {
+                    min: head.min.min(temp as i16),
+                    max: head.max.max(temp as i16),
+                    missing: head.missing,
+                },
+            });
+            self.total_count
+                .set(total_count.wrapping_add(((temp as i64) << COUNT_BITS) as u64 + 1));
+        } else {
+            self.promote().add_reading(temp);
+        }
+    }
+
+    pub fn add_missing(&self) {
+        match self.wide() {
+            Some(wide) => wide.add_missing(),
+            None => self.head.set(HeadOrWide {
+                head: PackedHead {
+                    missing: self.head().missing + 1,
+                    ..self.head()
+                },
+            }),
+        }
+    }
+
+    pub fn add(&self, t: &PackedTemperatureSummary) {
+        let fits = self.wide().is_none()
+            && t.wide().is_none()
+            && ((self.count() + t.count()) as u64) < COUNT_MASK - 1;
+        if !fits {
+            self.promote().add(&t.to_wide());
+            return;
+        }
+        let (head, t_head) = (self.head(), t.head());
+        self.head.set(HeadOrWide {
+            head: PackedHead {
+                min: head.min.min(t_head.min),
+                max: head.max.max(t_head.max),
+                missing: head.missing + t_head.missing,
+            },
+        });
+        // The counts don't carry into the totals, and the totals wrap
+        // like two's complement.
+        self.total_count
+            .set(self.total_count.get().wrapping_add(t.total_count.get()));
+    }
+
+    /// Copies the summary into a `TemperatureSummary`.
+    pub fn to_wide(&self) -> TemperatureSummary {
+        if let Some(wide) = self.wide() {
+            let copy = TemperatureSummary::default();
+            copy.add(wide);
+            return copy;
+        }
+        TemperatureSummary {
+            min: Cell::new(self.min()),
+            max: Cell::new(self.max()),
+            total: Cell::new(self.total()),
+            count: Cell::new(self.count()),
+            missing: Cell::new(self.missing()),
+        }
+    }
+
+    /// Moves the readings to a boxed `TemperatureSummary` if they aren't
+    /// already in one, and returns it.
+    #[cold]
+    #[inline(never)]
+    fn promote(&self) -> &TemperatureSummary {
+        if let Some(wide) = self.wide() {
+            return wide;
+        }
+        let wide = Box::into_raw(Box::new(self.to_wide()));
+        self.head.set(HeadOrWide { wide });
+        self.total_count.set(PROMOTED);
+        unsafe { &*wide }
+    }
+}
+
+impl Default for PackedTemperatureSummary {
+    fn default() -> Self {
+        Self {
+            head: Cell::new(HeadOrWide {
+                head: PackedHead {
+                    min: i16::MAX,
+                    max: i16::MIN,
+                    missing: 0,
+                },
+            }),
+            total_count: Cell::new(0),
+        }
+    }
+}
+
+impl Drop for PackedTemperatureSummary {
+    fn drop(&mut self) {
+        if self.wide().is_some() {
+            drop(unsafe { Box::from_raw(self.head.get().wide) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::temperature_summary::{COUNT_MASK, PackedTemperatureSummary, TemperatureSummary};
+
+    fn assert_same(packed: &PackedTemperatureSummary, wide: &TemperatureSummary) {
+        assert_eq!(packed.count(), wide.count());
+        assert_eq!(packed.missing(), wide.missing());
+        assert_eq!(packed.min(), wide.min());
+        assert_eq!(packed.max(), wide.max());
+        if wide.count() != 0 {
+            assert_eq!(packed.avg(), wide.avg());
+        }
+    }
+
+    #[test]
+    fn test_packed_summary_matches_wide() {
+        let packed = PackedTemperatureSummary::default();
+        let wide = TemperatureSummary::default();
+        assert_same(&packed, &wide);
+        for temp in [-999, 999, 0, -1, 1, i16::MIN as i32, i16::MAX as i32, 7] {
+            packed.add_reading(temp);
+            wide.add_reading(temp);
+            assert_same(&packed, &wide);
+        }
+        packed.add_missing();
+        wide.add_missing();
+        assert_same(&packed, &wide);
+        assert!(packed.wide().is_none());
+    }
+
+    #[test]
+    fn test_packed_summary_promotes_wide_readings() {
+        for temp in [i16::MAX as i32 + 1, i16::MIN as i32 - 1, 123_456, -123_456] {
+            let packed = PackedTemperatureSummary::default();
+            let wide = TemperatureSummary::default();
+            for temp in [-5, temp, 12] {
+                packed.add_reading(temp);
+                wide.add_reading(temp);
+            }
+            packed.add_missing();
+            wide.add_missing();
+            assert!(packed.wide().is_some());
+            assert_same(&packed, &wide);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_packed_summary_promotes_before_count_overflows() {
+        let packed = PackedTemperatureSummary::default();
+        let wide = TemperatureSummary::default();
+        for i in 0..COUNT_MASK + 10 {
+            let temp = if i % 2 == 0 { i16::MIN } else { i16::MAX - 1 } as i32;
+            packed.add_reading(temp);
+            wide.add_reading(temp);
+        }
+        assert!(packed.wide().is_some());
+        assert_same(&packed, &wide);
+    }
+
+    #[test]
+    fn test_packed_summaries_add() {
+        let readings = [[-300, 250, 1].as_slice(), &[], &[40_000], &[-2, -7]];
+        for a in readings {
+            for b in readings {
+                let (packed, packed_b) = (
+                    PackedTemperatureSummary::default(),
+                    PackedTemperatureSummary::default(),
+                );
+                let (wide, wide_b) = (TemperatureSummary::default(), TemperatureSummary::default());
+                for &temp in a {
+                    packed.add_reading(temp);
+                    wide.add_reading(temp);
+                }
+                for &temp in b {
+                    packed_b.add_reading(temp);
+                    wide_b.add_reading(temp);
+                }
+                packed_b.add_missing();
+                wide_b.add_missing();
+                packed.add(&packed_b);
+                wide.add(&wide_b);
+                assert_same(&packed, &wide);
+            }
+        }
+    }
+
+    #[test]
+    fn test_packed_summary_is_16_bytes() {
+        assert_eq!(size_of::<PackedTemperatureSummary>(), 16);
     }
 }
@@ -0,0 +1,23 @@
+//! Test data generators, shared by the library's and the binary's tests.
+
+/// SplitMix64, which is plenty for generating test inputs.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// n distinct station names.
+pub fn names(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("Station {i}")).collect()
+}
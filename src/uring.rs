@@ -0,0 +1,375 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+};
+
+use allocator_api2::boxed::Box;
+use io_uring::{IoUring, opcode, types};
+
+use crate::{
+    error::{BrcError, BrcResult},
+    mmap_allocator::{AllocatorOptions, MmapAllocator},
+};
+
+/// The number of reads `UringReader` keeps in flight.
+pub const READS_IN_FLIGHT: usize = 4;
+
+/// Reads are aligned to this, which is enough for O_DIRECT on any block
+/// size up to a page.
+pub const READ_ALIGN: usize = 4096;
+
+/// The room in front of each read for the end of the line the previous
+/// read stopped in. Reads stay aligned since this is a multiple of
+/// `READ_ALIGN`. Longer lines are handed out on their own instead.
+const CARRY_SIZE: usize = READ_ALIGN;
+
+/// Reads a file with io_uring, as an alternative to mapping it.
+///
+/// The file is read in `read_size` chunks with `READS_IN_FLIGHT` of them
+/// in flight, each into its own buffer, and handed out in order by
+/// `next_chunk` as runs of whole lines. The partial line a read ends with
+/// is copied into the room in front of the next read's buffer, so lines
+/// never straddle chunks. Lines that don't fit there are collected in a
+/// buffer of their own, and handed out as a chunk of one line.
+///
+/// The file is opened with O_DIRECT where that's supported, so reads go
+/// straight to the buffers rather than through the page cache.
+pub struct UringReader {
+    file: File,
+    file_len: usize,
+    read_size: usize,
+    ring: IoUring,
+    buffers: Vec<Box<[u8], MmapAllocator>>,
+    /// The number of bytes of the read in each buffer that have completed.
+    filled: [usize; READS_IN_FLIGHT],
+    in_flight: usize,
+    /// The index of the next read to hand out.
+    next_read: usize,
+    /// The read the last chunk came from, whose buffer can be read into
+    /// again once the chunk is done with.
+    handed_out: Option<usize>,
+    skip_lines: usize,
+    /// The partial line the last read ended with.
+    carry: Vec<u8>,
+    /// The read and offset in its buffer to go on from after handing out
+    /// a line longer than `CARRY_SIZE`.
+    resume: Option<(usize, usize)>,
+}
+
+impl UringReader {
+    /// Opens path for reading `read_size` bytes at a time, which must be a
+    /// multiple of `READ_ALIGN`, skipping the first `skip_lines` lines.
+    pub fn open(path: &str, skip_lines: usize, read_size: usize) -> BrcResult<Self> {
+        if read_size == 0 || !read_size.is_multiple_of(READ_ALIGN) || read_size > u32::MAX as usize
+        {
+            return Err(BrcError::new(format!(
+                "Read size {read_size} must be a positive multiple of {READ_ALIGN} below 4GiB"
+            ))
+            .into());
+        }
+
+        // Some filesystems, like tmpfs, don't support O_DIRECT.
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+            .or_else(|_| File::open(path))
+            .map_err(|err| BrcError::new(format!("Failed to open {path}: {err}")))?;
+        let file_len = file.metadata()?.len() as usize;
+
+        let mut reader = UringReader {
+            file,
+            file_len,
+            read_size,
+            ring: IoUring::new(READS_IN_FLIGHT as u32)?,
+            buffers: Vec::new(),
+            filled: [0; READS_IN_FLIGHT],
+            in_flight: 0,
+            next_read: 0,
+            handed_out: None,
+            skip_lines,
+            carry: Vec::with_capacity(CARRY_SIZE),
+            resume: None,
+        };
+        for read in 0..READS_IN_FLIGHT.min(reader.reads()) {
+            let alloc = MmapAllocator::new(&AllocatorOptions {
+                request_hugepage: false,
+            });
+            reader.buffers.push(unsafe {
+                Box::new_zeroed_slice_in(CARRY_SIZE + read_size, alloc).assume_init()
+            });
+            reader.submit(read)?;
+        }
+        Ok(reader)
+    }
+
+    /// Returns the next run of whole lines in the file, or None at the
+    /// end of it. Only the last line of the file may be missing its
+    /// newline.
+    ///
+    /// The chunk is only valid until the next call, which reuses its
+    /// buffer.
+    pub fn next_chunk(&mut self) -> BrcResult<Option<&[u8]>> {
+        loop {
+            let (read, resume_at) = match self.resume.take() {
+                Some((read, start)) => (read, Some(start)),
+                None => {
+                    if let Some(read) = self.handed_out.take()
+                        && read + READS_IN_FLIGHT < self.reads()
+                    {
+                        self.filled[read % READS_IN_FLIGHT] = 0;
+                        self.submit(read + READS_IN_FLIGHT)?;
+                    }
+                    if self.next_read == self.reads() {
+                        return Ok(None);
+                    }
+
+                    let read = self.next_read;
+                    self.next_read += 1;
+                    self.handed_out = Some(read);
+                    self.wait(read)?;
+                    (read, None)
+                }
+            };
+
+            let last = read + 1 == self.reads();
+            let end = CARRY_SIZE + self.read_len(read);
+            let buffer = &mut self.buffers[read % READS_IN_FLIGHT];
+            let mut start = match resume_at {
+                Some(start) => start,
+                None if self.carry.len() > CARRY_SIZE => {
+                    // The line doesn't fit in front of the read, so it's
+                    // collected in the carry until it ends, and handed out
+                    // on its own.
+                    let line_end = match buffer[CARRY_SIZE..end].iter().position(|&c| c == b'\n') {
+                        Some(newline_idx) => CARRY_SIZE + newline_idx + 1,
+                        None => end,
+                    };
+                    self.carry.extend_from_slice(&buffer[CARRY_SIZE..line_end]);
+                    if line_end == end && !last {
+                        continue;
+                    }
+                    self.resume = Some((read, line_end));
+                    return Ok(Some(&self.carry));
+                }
+                None => {
+                    let start = CARRY_SIZE - self.carry.len();
+                    buffer[start..CARRY_SIZE].copy_from_slice(&self.carry);
+                    start
+                }
+            };
+
+            // Header lines may be arbitrarily long, so they're skipped
+            // here rather than carried.
+            while self.skip_lines > 0 && start < end {
+                start = match buffer[start..end].iter().position(|&c| c == b'\n') {
+                    Some(newline_idx) => {
+                        self.skip_lines -= 1;
+                        start + newline_idx + 1
+                    }
+                    None => end,
+                };
+            }
+
+            let lines_end = match buffer[start..end].iter().rposition(|&c| c == b'\n') {
+                _ if last => end,
+                Some(newline_idx) => start + newline_idx + 1,
+                None => start,
+            };
+            self.carry.clear();
+            self.carry.extend_from_slice(&buffer[lines_end..end]);
+
+            if start < lines_end {
+                return Ok(Some(
+                    &self.buffers[read % READS_IN_FLIGHT][start..lines_end],
+                ));
+            }
+        }
+    }
+
+    fn reads(&self) -> usize {
+        self.file_len.div_ceil(self.read_size)
+    }
+
+    fn read_len(&self, read: usize) -> usize {
+        self.read_size.min(self.file_len - read * self.read_size)
+    }
+
+    /// Queues the rest of `read` into its buffer.
+    fn submit(&mut self, read: usize) -> BrcResult<()> {
+        // O_DIRECT reads need an aligned offset too, so the rest of a short
+        // read starts from the aligned offset before where it stopped, and
+        // reads the bytes past that again.
+        let filled = self.filled[read % READS_IN_FLIGHT] / READ_ALIGN * READ_ALIGN;
+        self.filled[read % READS_IN_FLIGHT] = filled;
+        let offset = read * self.read_size + filled;
+        // O_DIRECT reads need an aligned length, even at the end of the
+        // file, and stop short at the end anyway.
+        let end = CARRY_SIZE + self.read_len(read).next_multiple_of(READ_ALIGN);
+        let buffer = &mut self.buffers[read % READS_IN_FLIGHT][CARRY_SIZE + filled..end];
+        let entry = opcode::Read::new(
+            types::Fd(self.file.as_raw_fd()),
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+        )
+        .offset(offset as u64)
+        .build()
+        .user_data(read as u64);
+        // There's an entry per read in flight. The buffer isn't touched
+        // again until the read completes, and outlives it, see drop.
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| BrcError::new("io_uring submission queue is full".to_owned()))?;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Waits for `read` to complete. Reads may complete in any order, and
+    /// short ones are resubmitted for the rest.
+    fn wait(&mut self, read: usize) -> BrcResult<()> {
+        while self.filled[read % READS_IN_FLIGHT] < self.read_len(read) {
+            match self.ring.submit_and_wait(1) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            };
+            loop {
+                let completion = self.ring.completion().next();
+                let Some(completion) = completion else {
+                    break;
+                };
+                self.in_flight -= 1;
+                let done = completion.user_data() as usize;
+                let len = match completion.result() {
+                    len if len < 0 => return Err(io::Error::from_raw_os_error(-len).into()),
+                    0 => return Err(BrcError::new("Input shrank while reading".to_owned()).into()),
+                    len => len as usize,
+                };
+                self.filled[done % READS_IN_FLIGHT] += len;
+                if self.filled[done % READS_IN_FLIGHT] < self.read_len(done) {
+                    self.submit(done)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UringReader {
+    fn drop(&mut self) {
+        // The kernel may still be writing to the buffers.
+        while self.in_flight > 0 {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => self.in_flight -= self.ring.completion().count(),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    std::mem::forget(std::mem::take(&mut self.buffers));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        temp_file::TempFile,
+        uring::{READ_ALIGN, UringReader},
+    };
+
+    /// Reads `data` back in chunks, checking that each is whole lines.
+    fn chunks(data: &[u8], skip_lines: usize, read_size: usize) -> Vec<u8> {
+        let file = TempFile::new("uring-test", data);
+        let mut reader = UringReader::open(file.path(), skip_lines, read_size).unwrap();
+        let mut read = Vec::new();
+        let mut last_newline = true;
+        while let Some(chunk) = reader.next_chunk().unwrap() {
+            assert!(last_newline, "chunk before the last doesn't end a line");
+            assert!(!chunk.is_empty());
+            last_newline = chunk.last() == Some(&b'\n');
+            read.extend_from_slice(chunk);
+        }
+        read
+    }
+
+    fn lines(count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| {
+                format!("station {};{}.{}\n", i * 7919 % 1000, i % 99, i % 10).into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_chunks_are_whole_lines() {
+        for count in [0, 1, 100, 1000, 5000] {
+            let data = lines(count);
+            for read_size in [READ_ALIGN, 3 * READ_ALIGN, 1 << 20] {
+                assert_eq!(chunks(&data, 0, read_size), data, "count={count}");
+                let mut no_newline = data.clone();
+                no_newline.pop();
+                assert_eq!(
+                    chunks(&no_newline, 0, read_size),
+                    no_newline,
+                    "count={count}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_chunks_lines_longer_than_carry() {
+        for long_len in [
+            READ_ALIGN - 1,
+            READ_ALIGN,
+            3 * READ_ALIGN + 7,
+            10 * READ_ALIGN,
+        ] {
+            let mut long_line = vec![b'#'; long_len];
+            long_line.extend_from_slice(b";1.0\n");
+            // The long line starts just before a read boundary, in the
+            // middle of the file, and ends it with or without a newline.
+            let mut data = lines(100);
+            data.truncate(READ_ALIGN - 10);
+            data.truncate(data.iter().rposition(|&c| c == b'\n').unwrap() + 1);
+            data.extend_from_slice(&long_line);
+            let middle = data.len();
+            data.extend_from_slice(&lines(1000));
+            data.extend_from_slice(&long_line);
+            for read_size in [READ_ALIGN, 2 * READ_ALIGN, 1 << 20] {
+                assert_eq!(
+                    chunks(&data[..middle], 0, read_size),
+                    &data[..middle],
+                    "long_len={long_len}"
+                );
+                assert_eq!(chunks(&data, 0, read_size), data, "long_len={long_len}");
+                assert_eq!(
+                    chunks(&data[..data.len() - 1], 0, read_size),
+                    &data[..data.len() - 1],
+                    "long_len={long_len}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_skips_header_lines_across_reads() {
+        let mut data = vec![b'#'; 3 * READ_ALIGN + 5];
+        data.extend_from_slice(b"\nheader\n");
+        let body = lines(2000);
+        data.extend_from_slice(&body);
+        assert_eq!(chunks(&data, 2, READ_ALIGN), body);
+        assert_eq!(chunks(&data, 2 + 2000, READ_ALIGN), b"");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_rejects_unaligned_read_size() {
+        for read_size in [0, 100, READ_ALIGN + 1] {
+            assert!(UringReader::open("/dev/null", 0, read_size).is_err());
+        }
+    }
+}